@@ -0,0 +1,122 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use errors::*;
+use locks::lock;
+
+/// Audit log with one JSON line for every request.
+pub struct AuditLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// The decision that was taken for a request and everything that is known about it.
+pub struct AuditRecord {
+    timestamp: SystemTime,
+    start: Instant,
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub allowed: bool,
+    pub rule: Option<&'static str>,
+    pub status: Option<u16>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub error: Option<String>,
+}
+
+impl AuditLog {
+    /// Opens the audit log in append mode. If `path` is `-` then records are
+    /// written in stdout.
+    pub fn open(path: &str) -> Result<AuditLog> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .chain_err(|| format!("Failed to open `{}` audit log", path))?;
+            Box::new(file)
+        };
+
+        Ok(AuditLog {
+            out: Mutex::new(out),
+        })
+    }
+
+    pub fn log(&self, record: &AuditRecord) {
+        let json = json!({
+            "timestamp": format_timestamp(record.timestamp),
+            "uid": record.uid,
+            "pid": record.pid,
+            "method": record.method,
+            "path": record.path,
+            "decision": if record.allowed { "allow" } else { "deny" },
+            "rule": record.rule,
+            "status": record.status,
+            "bytes_in": record.bytes_in,
+            "bytes_out": record.bytes_out,
+            "duration_ms": duration_ms(record.start),
+            "error": record.error,
+        });
+
+        let mut line = json.to_string();
+        line.push('\n');
+
+        let mut out = lock(&self.out);
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            error!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+impl AuditRecord {
+    pub fn new() -> AuditRecord {
+        AuditRecord {
+            timestamp: SystemTime::now(),
+            start: Instant::now(),
+            uid: None,
+            pid: None,
+            method: None,
+            path: None,
+            allowed: false,
+            rule: None,
+            status: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            error: None,
+        }
+    }
+}
+
+fn duration_ms(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_micros()) / 1000.0
+}
+
+/// Formats `time` as RFC 3339 in UTC, e.g. `2018-07-01T12:30:00.123Z`.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60,
+            since_epoch.subsec_millis())
+}
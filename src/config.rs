@@ -1,12 +1,17 @@
 pub extern crate config;
 
 use std::path::PathBuf;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::Duration;
 
 use httparse;
 use regex::Regex;
 use url::Url;
+use url::form_urlencoded;
 use clap::ArgMatches;
 
 use errors::*;
@@ -15,14 +20,343 @@ use errors::*;
 pub struct Config {
     pub docker_host: Url,
     pub docker_guard_dir: PathBuf,
-    http_path_whitelist: Vec<(Regex, Option<FilterFn>)>,
+    http_path_whitelist: Vec<Rule>,
+    http_path_rewrites: Vec<(Regex, RewriteFn)>,
     env_whitelist: HashSet<String>,
+    label_whitelist: HashSet<String>,
+    scope_labels: Vec<String>,
+    min_api_version: Option<ApiVersion>,
+    max_api_version: Option<ApiVersion>,
+    pinned_api_version: Option<ApiVersion>,
+    /// Matches the versioned paths, e.g. `/v1.37/info`.
+    api_version_re: Regex,
+    pub audit_log: Option<String>,
+    pub metrics_listen: Option<String>,
+    rate_limit: Option<RateLimit>,
+    rule_rate_limits: HashMap<String, RateLimit>,
+    pub workers: usize,
+    pub max_connections: usize,
+    pub max_connections_per_client: Option<usize>,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub headers: HeaderRules,
+}
+
+/// Size limits of the HTTP messages.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Size of the headers of requests and responses, in bytes.
+    pub header_bytes: usize,
+    /// Number of headers of requests and responses.
+    pub headers: usize,
+    /// Size of the body of a request, in bytes.
+    pub request_body: usize,
+    /// Size of the body of a response that is filtered, in bytes. The other
+    /// responses are not limited.
+    pub response_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            header_bytes: 64 * 1024,
+            headers: 100,
+            request_body: 1024 * 1024,
+            response_body: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    fn from_settings(settings: &config::Config) -> Result<Limits> {
+        let default = Limits::default();
+        let get = |key: &str| get_positive_int(settings, &format!("limits.{}", key));
+
+        Ok(Limits {
+            header_bytes: get("header_bytes")?.unwrap_or(default.header_bytes),
+            headers: get("headers")?.unwrap_or(default.headers),
+            request_body: get("request_body")?.unwrap_or(default.request_body),
+            response_body: get("response_body")?.unwrap_or(default.response_body),
+        })
+    }
+}
+
+/// Headers that are removed from, or added to, the forwarded messages.
+#[derive(Clone, Debug)]
+pub struct HeaderRules {
+    pub request_remove: Vec<String>,
+    pub request_add: Vec<(String, String)>,
+    pub response_remove: Vec<String>,
+    pub response_add: Vec<(String, String)>,
+}
+
+impl HeaderRules {
+    fn from_settings(settings: &config::Config) -> Result<HeaderRules> {
+        Ok(HeaderRules {
+            request_remove: get_header_names(settings, "headers.request_remove")?
+                .unwrap_or_else(|| vec!["Authorization".to_owned(), "X-Registry-Auth".to_owned()]),
+            request_add: get_header_fields(settings, "headers.request_add")?,
+            response_remove: get_header_names(settings, "headers.response_remove")?.unwrap_or_default(),
+            response_add: get_header_fields(settings, "headers.response_add")?,
+        })
+    }
+}
+
+/// Headers that frame the messages, or manage the connections, so they cannot be changed.
+const RESERVED_HEADERS: &[&str] = &["Connection", "Content-Length", "Transfer-Encoding", "Upgrade"];
+
+fn check_header_name(key: &str, name: &str) -> Result<()> {
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if name.is_empty() || !name.chars().all(is_token) {
+        bail!("{} in config file has invalid header name: {}", key, name);
+    }
+    if RESERVED_HEADERS.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+        bail!("{} in config file cannot change {} header", key, name);
+    }
+    Ok(())
+}
+
+/// Reads an optional list of strings from config file.
+fn get_str_list(settings: &config::Config, key: &str) -> Result<Option<Vec<String>>> {
+    match settings.get_array(key) {
+        Ok(values) => {
+            let values: ::std::result::Result<Vec<String>, _> = values.into_iter().map(|x| x.into_str()).collect();
+            Ok(Some(values.chain_err(|| format!("{} in config file must be a list of strings", key))?))
+        }
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e).chain_err(|| format!("{} in config file must be a list", key)),
+    }
+}
+
+/// Reads an optional list of header names from config file.
+fn get_header_names(settings: &config::Config, key: &str) -> Result<Option<Vec<String>>> {
+    let names = get_str_list(settings, key)?;
+    for name in names.iter().flatten() {
+        check_header_name(key, name)?;
+    }
+    Ok(names)
+}
+
+/// Reads a list of `Name: value` headers from config file.
+fn get_header_fields(settings: &config::Config, key: &str) -> Result<Vec<(String, String)>> {
+    let mut result = Vec::new();
+    for field in get_str_list(settings, key)?.unwrap_or_default() {
+        let mut parts = field.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(value) => value.trim(),
+            None => bail!("{} in config file must have `Name: value` headers: {}", key, field),
+        };
+        check_header_name(key, name)?;
+        if value.chars().any(|c| c.is_control()) {
+            bail!("{} in config file has invalid value of {} header", key, name);
+        }
+        result.push((name.to_owned(), value.to_owned()));
+    }
+    Ok(result)
+}
+
+/// Time limits of the connections. `None` means that there is no limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Receiving the headers of a request. It includes the time that the connection
+    /// is idle before the request.
+    pub request_headers: Option<Duration>,
+    pub request_body: Option<Duration>,
+    /// Connecting to docker. Only tcp docker hosts are supported.
+    pub connect: Option<Duration>,
+    /// Receiving the headers of a response, after the request is forwarded.
+    pub response_headers: Option<Duration>,
+    /// Time without any data from docker while the content of a response is received.
+    pub response_idle: Option<Duration>,
+    /// Each write of a response to the client. Requests are handled by blocking
+    /// workers, so a client that does not read would hold one.
+    pub response_write: Option<Duration>,
+    /// Time without any data in either direction of an upgraded connection.
+    pub upgraded_idle: Option<Duration>,
+}
+
+impl Timeouts {
+    fn from_settings(settings: &config::Config) -> Result<Timeouts> {
+        let get = |key: &str| -> Result<Option<Duration>> {
+            let secs = get_positive_int(settings, &format!("timeouts.{}", key))?;
+            Ok(secs.map(|secs| Duration::from_secs(secs as u64)))
+        };
+
+        Ok(Timeouts {
+            request_headers: Some(get("request_headers")?.unwrap_or(Duration::from_secs(30))),
+            request_body: Some(get("request_body")?.unwrap_or(Duration::from_secs(60))),
+            connect: Some(get("connect")?.unwrap_or(Duration::from_secs(10))),
+            response_headers: Some(get("response_headers")?.unwrap_or(Duration::from_secs(60))),
+            response_idle: Some(get("response_idle")?.unwrap_or(Duration::from_secs(60))),
+            response_write: Some(get("response_write")?.unwrap_or(Duration::from_secs(30))),
+            upgraded_idle: get("upgraded_idle")?,
+        })
+    }
+}
+
+/// Limit of a token bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Tokens that are added per second.
+    pub rate: f64,
+    /// Maximum number of tokens.
+    pub burst: f64,
+}
+
+impl RateLimit {
+    fn from_table(table: HashMap<String, config::Value>) -> Result<RateLimit> {
+        let rate = match table.get("rate") {
+            Some(rate) => rate.clone().into_float()?,
+            None => bail!("rate is missing"),
+        };
+        // by default allow a burst of one second
+        let burst = match table.get("burst") {
+            Some(burst) => burst.clone().into_float()?,
+            None => rate.max(1.0),
+        };
+
+        if rate <= 0.0 || burst < 1.0 {
+            bail!("rate must be positive and burst must be at least 1");
+        }
+
+        Ok(RateLimit { rate, burst })
+    }
+}
+
+/// Reads the rate limit of all requests and the rate limits of the rules from config file.
+fn get_rate_limits(settings: &config::Config) -> Result<(Option<RateLimit>, HashMap<String, RateLimit>)> {
+    let mut table = match settings.get_table("rate_limit") {
+        Ok(table) => table,
+        Err(config::ConfigError::NotFound(_)) => return Ok((None, HashMap::new())),
+        Err(e) => return Err(e).chain_err(|| "rate_limit in config file must be a table"),
+    };
+
+    let mut rule_rate_limits = HashMap::new();
+    if let Some(rules) = table.remove("rules") {
+        let rules = rules.into_table().chain_err(|| "rate_limit.rules in config file must be a table")?;
+        for (rule, limit) in rules {
+            let limit = limit
+                .into_table()
+                .map_err(Error::from)
+                .and_then(RateLimit::from_table)
+                .chain_err(|| format!("Invalid rate limit of `{}` rule", rule))?;
+            rule_rate_limits.insert(rule, limit);
+        }
+    }
+
+    let rate_limit = if table.is_empty() {
+        None
+    } else {
+        Some(RateLimit::from_table(table).chain_err(|| "Invalid rate_limit in config file")?)
+    };
+
+    Ok((rate_limit, rule_rate_limits))
+}
+
+/// A white-listed path of the docker API.
+#[derive(Clone)]
+pub struct Rule {
+    pub name: &'static str,
+    re: Regex,
+    /// If `None` then no extra filtering is needed and content must be forwarded.
+    pub filter: Option<FilterFn>,
+    /// Name of the filter function, e.g. `filters::list`.
+    filter_name: Option<&'static str>,
+    /// Query parameters that are forwarded, and the regexes that their values must match.
+    query_params: Vec<(&'static str, Regex)>,
+}
+
+impl Rule {
+    pub fn regex(&self) -> &str {
+        self.re.as_str()
+    }
+
+    pub fn filter_name(&self) -> Option<&'static str> {
+        self.filter_name
+    }
+
+    pub fn methods(&self) -> &'static str {
+        if self.filter.is_some() { "GET" } else { "GET,HEAD" }
+    }
+
+    pub fn query_params(&self) -> Vec<&str> {
+        self.query_params.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Returns the query without the parameters that are not allowed.
+    ///
+    /// On error (i.e. value of an allowed parameter is invalid) it returns the
+    /// message that must be sent to the client.
+    pub fn filter_query(&self, query: &str) -> ::std::result::Result<String, String> {
+        let mut filtered = form_urlencoded::Serializer::new(String::new());
+
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            match self.query_params.iter().find(|(x, _)| *x == name) {
+                Some((_, re)) if re.is_match(&value) => {
+                    filtered.append_pair(&name, &value);
+                }
+                Some(_) => return Err(format!("invalid value of `{}` query parameter", name)),
+                None => debug!("Strip query parameter: {}", name),
+            }
+        }
+
+        Ok(filtered.finish())
+    }
+}
+
+/// Config file that is used if none is given. It is ignored if it does not exist.
+pub const DEFAULT_CONFIG_FILE: &str = "/etc/docker-guard/config.yml";
+
+/// Docker API version, e.g. `1.37`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ApiVersion(u32, u32);
+
+impl FromStr for ApiVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ApiVersion> {
+        let mut parts = s.splitn(2, '.');
+        let major = parts.next().unwrap_or("").parse()?;
+        let minor = parts.next().ok_or_else(|| format!("Invalid API version: {}", s))?.parse()?;
+        Ok(ApiVersion(major, minor))
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+/// Reads an optional positive integer from config file.
+fn get_positive_int(settings: &config::Config, key: &str) -> Result<Option<usize>> {
+    match settings.get_int(key) {
+        Ok(value) if value > 0 => Ok(Some(value as usize)),
+        Ok(_) => bail!("{} in config file must be positive", key),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e).chain_err(|| format!("{} in config file must be an integer", key)),
+    }
+}
+
+/// Reads an optional API version from config file.
+fn get_api_version(settings: &config::Config, key: &str) -> Result<Option<ApiVersion>> {
+    match settings.get_str(key) {
+        Ok(version) => {
+            let version = version.parse().chain_err(|| format!("{} in config file is invalid", key))?;
+            Ok(Some(version))
+        }
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
 }
 
 pub type FilterFn = fn(&Config, &httparse::Request, &httparse::Response, &mut Vec<u8>) -> Result<bool>;
+/// Rewrites a request path. On error it returns the message that must be sent to the client.
+pub type RewriteFn = fn(&Config, &str) -> ::std::result::Result<String, String>;
 
 impl Config {
-    pub fn from_arg_matches(matches: ArgMatches) -> Result<Config> {
+    pub fn from_arg_matches(matches: &ArgMatches) -> Result<Config> {
         let docker_host = matches.value_of("DOCKER_HOST").unwrap();
         let docker_host = Url::parse(docker_host).chain_err(|| format!("Invalid uri: {}", docker_host))?;
 
@@ -32,12 +366,29 @@ impl Config {
                 None => HashSet::new(),
             };
 
+        let mut label_whitelist =
+            match matches.values_of("LABEL_WHITELIST") {
+                Some(labels) => labels.into_iter().map(|x| x.to_owned()).collect(),
+                None => HashSet::new(),
+            };
+
+        let mut scope_labels: Vec<String> =
+            match matches.values_of("SCOPE_LABELS") {
+                Some(labels) => labels.into_iter().map(|x| x.to_owned()).collect(),
+                None => Vec::new(),
+            };
+
         let mut settings = config::Config::new();
         settings.set_default("env_whitelist", Vec::<String>::new())?;
+        settings.set_default("label_whitelist", Vec::<String>::new())?;
+        settings.set_default("scope_labels", Vec::<String>::new())?;
 
         if let Some(config_file) = matches.value_of("CONFIG") {
             if Path::new(config_file).is_file() {
-                settings.merge(config::File::with_name(&config_file))?;
+                settings.merge(config::File::with_name(config_file))?;
+            } else if config_file != DEFAULT_CONFIG_FILE {
+                // only the default config file is optional
+                bail!("Config file does not exist: {}", config_file);
             }
         }
 
@@ -47,33 +398,176 @@ impl Config {
                              .into_iter()
                              .filter_map(|v| v.into_str().ok()));
 
+        label_whitelist.extend(settings
+                               .get_array("label_whitelist")
+                               .chain_err(|| "label_whitelist in config file must be a list, not a single value")?
+                               .into_iter()
+                               .filter_map(|v| v.into_str().ok()));
+
+        scope_labels.extend(settings
+                            .get_array("scope_labels")
+                            .chain_err(|| "scope_labels in config file must be a list, not a single value")?
+                            .into_iter()
+                            .filter_map(|v| v.into_str().ok()));
+
+        let audit_log = match matches.value_of("AUDIT_LOG") {
+            Some(audit_log) => Some(audit_log.to_owned()),
+            None => settings.get_str("audit_log").ok(),
+        };
+
+        let metrics_listen = match matches.value_of("METRICS_LISTEN") {
+            Some(addr) => Some(addr.to_owned()),
+            None => settings.get_str("metrics_listen").ok(),
+        };
+
+        let (rate_limit, rule_rate_limits) = get_rate_limits(&settings)?;
+
+        let workers = get_positive_int(&settings, "workers")?.unwrap_or(32);
+        let max_connections = get_positive_int(&settings, "max_connections")?.unwrap_or(1024);
+        let max_connections_per_client = get_positive_int(&settings, "max_connections_per_client")?;
+        let timeouts = Timeouts::from_settings(&settings)?;
+        let limits = Limits::from_settings(&settings)?;
+        let headers = HeaderRules::from_settings(&settings)?;
+
+        let min_api_version = get_api_version(&settings, "min_api_version")?;
+        let max_api_version = get_api_version(&settings, "max_api_version")?;
+        let pinned_api_version = get_api_version(&settings, "api_version")?;
+
+        if let (Some(min), Some(max)) = (min_api_version, max_api_version) {
+            if min > max {
+                bail!("min_api_version ({}) is greater than max_api_version ({})", min, max);
+            }
+        }
+
+        if let Some(pinned) = pinned_api_version {
+            if min_api_version.map_or(false, |min| pinned < min) ||
+               max_api_version.map_or(false, |max| pinned > max) {
+                bail!("api_version ({}) is out of the min_api_version/max_api_version range", pinned);
+            }
+        }
+
         Ok(Config {
-            docker_host: docker_host,
+            docker_host,
             docker_guard_dir: PathBuf::from("/var/run/docker-guard"),
             http_path_whitelist: Vec::new(),
-            env_whitelist: env_whitelist,
+            http_path_rewrites: Vec::new(),
+            env_whitelist,
+            label_whitelist,
+            scope_labels,
+            min_api_version,
+            max_api_version,
+            pinned_api_version,
+            api_version_re: Regex::new(r"^/v([0-9\.]+)([/?].*)?$").unwrap(),
+            audit_log,
+            metrics_listen,
+            rate_limit,
+            rule_rate_limits,
+            workers,
+            max_connections,
+            max_connections_per_client,
+            timeouts,
+            limits,
+            headers,
         })
     }
 
-    pub fn allow_http_path(&mut self, str_re: &str) -> Result<()> {
+    /// Checks the settings that otherwise would fail only when they are used.
+    pub fn validate(&self) -> Result<()> {
+        match self.docker_host.scheme() {
+            "unix" | "tcp" => {}
+            scheme => bail!("Unsupported docker host scheme: {}", scheme),
+        }
+
+        if let Some(ref addr) = self.metrics_listen {
+            addr.to_socket_addrs().chain_err(|| format!("Invalid metrics_listen address: {}", addr))?;
+        }
+
+        for rule in self.rule_rate_limits.keys() {
+            if !self.http_path_whitelist.iter().any(|x| x.name == rule) {
+                bail!("Rate limit of unknown rule: {}", rule);
+            }
+        }
+
+        if let Some(ref path) = self.audit_log {
+            let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty());
+            if let Some(dir) = dir {
+                if path != "-" && !dir.is_dir() {
+                    bail!("Directory of audit_log does not exist: {}", dir.to_string_lossy());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn allow_http_path(&mut self, name: &'static str, str_re: &str) -> Result<()> {
         let re = Regex::new(str_re).chain_err(|| format!("Invalid regex: {}", str_re))?;
-        self.http_path_whitelist.push((re, None));
+        self.http_path_whitelist.push(Rule { name, re, filter: None, filter_name: None, query_params: Vec::new() });
         Ok(())
     }
 
-    pub fn filter_http_path(&mut self, str_re: &str, filter_content: FilterFn) -> Result<()> {
+    /// `filter_content` is the name of the filter and the filter function, as
+    /// they are given by `filter!` macro.
+    pub fn filter_http_path(&mut self, name: &'static str, str_re: &str,
+                            filter_content: (&'static str, FilterFn)) -> Result<()> {
         let re = Regex::new(str_re).chain_err(|| format!("Invalid regex: {}", str_re))?;
-        self.http_path_whitelist.push((re, Some(filter_content)));
+        let (filter_name, filter) = filter_content;
+        self.http_path_whitelist.push(Rule {
+            name,
+            re,
+            filter: Some(filter),
+            filter_name: Some(filter_name),
+            query_params: Vec::new(),
+        });
         Ok(())
     }
 
-    /// Returns `None` if path is not allowed, otherwise `Some(Option<FilterFn>)`.
-    /// If `Option<FilterFn>` is `None` then no extra filtering is needed and
-    /// content must be forwarded.
-    pub fn match_http_path(&self, path: &str) -> Option<Option<FilterFn>> {
-        for (re, filter_fn) in &self.http_path_whitelist {
+    /// Allows the query parameters `params` in the requests that match the `rule`,
+    /// if their values match the given regexes. Other parameters are stripped.
+    pub fn allow_query_params(&mut self, rule: &str, params: &[(&'static str, &str)]) -> Result<()> {
+        let mut query_params = Vec::new();
+        for (name, str_re) in params {
+            let re = Regex::new(str_re).chain_err(|| format!("Invalid regex: {}", str_re))?;
+            query_params.push((*name, re));
+        }
+
+        match self.http_path_whitelist.iter_mut().find(|x| x.name == rule) {
+            Some(rule) => rule.query_params.extend(query_params),
+            None => bail!("Query parameters of unknown rule: {}", rule),
+        }
+        Ok(())
+    }
+
+    /// Rewrite the request path of the allowed paths that match `str_re`,
+    /// before it is forwarded to docker.
+    pub fn rewrite_http_path(&mut self, str_re: &str, rewrite: RewriteFn) -> Result<()> {
+        let re = Regex::new(str_re).chain_err(|| format!("Invalid regex: {}", str_re))?;
+        self.http_path_rewrites.push((re, rewrite));
+        Ok(())
+    }
+
+    /// Returns `None` if path is not allowed, otherwise the first rule that matches.
+    /// The query is not matched, it is checked by `Rule::filter_query`.
+    ///
+    /// All white-listed paths are read-only, so only `GET` and `HEAD` requests can
+    /// match. Otherwise paths such as `/networks/ID` would allow `DELETE` too.
+    /// `HEAD` requests can match only the rules that are not filtered, since their
+    /// responses have the headers of the unfiltered content (e.g. `Content-Length`).
+    pub fn match_http_path(&self, method: &str, path: &str) -> Option<&Rule> {
+        if method != "GET" && method != "HEAD" {
+            return None;
+        }
+
+        let path = path.split('?').next().unwrap_or("");
+        self.http_path_whitelist.iter()
+            .find(|rule| rule.re.is_match(path))
+            .filter(|rule| method == "GET" || rule.filter.is_none())
+    }
+
+    pub fn match_http_rewrite(&self, path: &str) -> Option<RewriteFn> {
+        for (re, rewrite) in &self.http_path_rewrites {
             if re.is_match(path) {
-                return Some(*filter_fn);
+                return Some(*rewrite);
             }
         }
         None
@@ -82,4 +576,113 @@ impl Config {
     pub fn whitelisted_env(&self, env_var_name: &str) -> bool {
         self.env_whitelist.contains(env_var_name)
     }
+
+    /// Labels are white-listed either by their exact name, or by a prefix
+    /// followed by `*` (e.g. `traefik.*`).
+    pub fn whitelisted_label(&self, label_name: &str) -> bool {
+        self.label_whitelist.iter().any(|x| {
+            if x.ends_with('*') {
+                label_name.starts_with(&x[..x.len() - 1])
+            } else {
+                x == label_name
+            }
+        })
+    }
+
+    /// Label filters (`key` or `key=value`) that every container must match
+    /// to be visible to the clients.
+    pub fn scope_labels(&self) -> &[String] {
+        &self.scope_labels
+    }
+
+    /// Checks the API version of the request path against `min_api_version` and
+    /// `max_api_version`. If the path is unversioned and `api_version` is set, it
+    /// returns the path pinned to that version.
+    ///
+    /// On error it returns the message that must be sent to the client.
+    pub fn check_api_version(&self, path: &str) -> ::std::result::Result<Option<String>, String> {
+        let caps = match self.api_version_re.captures(path) {
+            Some(caps) => caps,
+            None => {
+                return Ok(self.pinned_api_version.map(|pinned| format!("/v{}{}", pinned, path)));
+            }
+        };
+
+        let version = caps.get(1).unwrap().as_str();
+        let version: ApiVersion = match version.parse() {
+            Ok(version) => version,
+            Err(_) => return Err(format!("invalid API version: {}", version)),
+        };
+
+        if let Some(min) = self.min_api_version {
+            if version < min {
+                return Err(format!("client version {} is too old. Minimum supported API version is {}, \
+                                    please upgrade your client to a newer version", version, min));
+            }
+        }
+
+        if let Some(max) = self.max_api_version {
+            if version > max {
+                return Err(format!("client version {} is too new. Maximum supported API version is {}",
+                                   version, max));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Clamps an API version that is advertised by docker into the range of
+    /// `min_api_version` and `max_api_version`. Returns `None` if no change is needed.
+    pub fn clamp_api_version(&self, version: &str) -> Option<String> {
+        let version: ApiVersion = version.parse().ok()?;
+
+        if let Some(min) = self.min_api_version {
+            if version < min {
+                return Some(min.to_string());
+            }
+        }
+
+        if let Some(max) = self.max_api_version {
+            if version > max {
+                return Some(max.to_string());
+            }
+        }
+
+        None
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.http_path_whitelist
+    }
+
+    pub fn rewritten_paths(&self) -> Vec<&str> {
+        self.http_path_rewrites.iter().map(|(re, _)| re.as_str()).collect()
+    }
+
+    pub fn env_whitelist(&self) -> Vec<&str> {
+        let mut envs: Vec<&str> = self.env_whitelist.iter().map(|x| x.as_str()).collect();
+        envs.sort();
+        envs
+    }
+
+    pub fn label_whitelist(&self) -> Vec<&str> {
+        let mut labels: Vec<&str> = self.label_whitelist.iter().map(|x| x.as_str()).collect();
+        labels.sort();
+        labels
+    }
+
+    /// Returns the minimum, maximum, and pinned API versions.
+    pub fn api_versions(&self) -> (Option<ApiVersion>, Option<ApiVersion>, Option<ApiVersion>) {
+        (self.min_api_version, self.max_api_version, self.pinned_api_version)
+    }
+
+    /// Rate limit of all the requests of a client.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+
+    /// Rate limit of the requests of a client that match `rule`.
+    pub fn rule_rate_limit(&self, rule: &str) -> Option<RateLimit> {
+        self.rule_rate_limits.get(rule).cloned()
+    }
 }
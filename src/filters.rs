@@ -2,13 +2,115 @@ use regex::Regex;
 use serde_json;
 use serde_json::Value;
 use httparse;
+use url::form_urlencoded;
 
 use config::Config;
 use errors::*;
 
+/// Returns only the white-listed variables of a `Config.Env` list.
+fn whitelisted_env(config: &Config, envs: &Value) -> Vec<Value> {
+    let mut new_env = Vec::new();
+
+    if let Value::Array(ref envs) = envs {
+        let re = Regex::new("^([^=]+)=(.+)$").unwrap();
+        for env in envs {
+            if let Value::String(env) = env {
+                if let Some(caps) = re.captures(env) {
+                    let name = caps.get(1).unwrap().as_str();
+                    if config.whitelisted_env(name) {
+                        new_env.push(json!(env));
+                    }
+                }
+            }
+        }
+    }
+
+    new_env
+}
+
+/// Returns only the white-listed labels of a `Labels` object.
+fn whitelisted_labels(config: &Config, labels: &Value) -> Value {
+    let mut new_labels = json!({});
+
+    if let Value::Object(ref labels) = labels {
+        for (name, value) in labels {
+            if config.whitelisted_label(name) {
+                new_labels[name] = value.clone();
+            }
+        }
+    }
+
+    new_labels
+}
+
+/// Returns `true` if `labels` match all the scope labels of the configuration.
+fn in_scope(config: &Config, labels: &Value) -> bool {
+    config.scope_labels().iter().all(|filter| {
+        let mut parts = filter.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        match parts.next() {
+            Some(value) => labels[name] == json!(value),
+            None => !labels[name].is_null(),
+        }
+    })
+}
+
+/// Request filter for `docker ps`
+///
+/// Scope labels are merged into the `filters` query parameter of the client,
+/// so docker returns only the containers that are in scope.
+///
+/// Invalid `filters` are rejected, since they cannot be merged.
+pub fn list_query(config: &Config, path: &str) -> ::std::result::Result<String, String> {
+    if config.scope_labels().is_empty() {
+        return Ok(path.to_owned());
+    }
+
+    let mut parts = path.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next().unwrap_or("");
+
+    let mut filters: Option<Value> = None;
+    let mut new_query = form_urlencoded::Serializer::new(String::new());
+
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        if name == "filters" {
+            if filters.is_some() {
+                return Err("multiple `filters` query parameters".to_owned());
+            }
+            let value = serde_json::from_str(&value).map_err(|_| "`filters` query parameter is not valid JSON")?;
+            filters = Some(value);
+        } else {
+            new_query.append_pair(&name, &value);
+        }
+    }
+
+    let mut filters = filters.unwrap_or_else(|| json!({}));
+    if !filters.is_object() {
+        return Err("`filters` query parameter must be a JSON object".to_owned());
+    }
+
+    match filters["label"] {
+        Value::Null => filters["label"] = json!(config.scope_labels()),
+        Value::Array(ref mut labels) => {
+            labels.extend(config.scope_labels().iter().map(|x| json!(x)));
+        }
+        // legacy format: {"label": {"key=value": true}}
+        Value::Object(ref mut labels) => {
+            for label in config.scope_labels() {
+                labels.insert(label.clone(), json!(true));
+            }
+        }
+        _ => return Err("`label` filter must be a list".to_owned()),
+    }
+
+    new_query.append_pair("filters", &filters.to_string());
+    Ok(format!("{}?{}", path, new_query.finish()))
+}
+
 /// Filter for `docker ps`
 pub fn list(
-    _config: &Config,
+    config: &Config,
     _req: &httparse::Request,
     res: &httparse::Response,
     content: &mut Vec<u8>,
@@ -22,6 +124,9 @@ pub fn list(
 
     if let Value::Array(ref containers) = json {
         for container in containers {
+            if !in_scope(config, &container["Labels"]) {
+                continue;
+            }
             new_list.push(json!({
                 "Id": container["Id"],
                 "Created": container["Created"],
@@ -46,22 +151,12 @@ pub fn inspect(
     }
 
     let json: Value = serde_json::from_slice(&content[..])?;
-    let mut new_env = Vec::new();
-
-    if let Value::Array(ref envs) = json["Config"]["Env"] {
-        let re = Regex::new("^([^=]+)=(.+)$").unwrap();
-        for env in envs {
-            if let Value::String(env) = env {
-                if let Some(caps) = re.captures(env) {
-                    let name = caps.get(1).unwrap().as_str();
-                    if config.whitelisted_env(name) {
-                        new_env.push(json!(env));
-                    }
-                }
-            }
-        }
+    if !in_scope(config, &json["Config"]["Labels"]) {
+        return Ok(false);
     }
 
+    let new_env = whitelisted_env(config, &json["Config"]["Env"]);
+
     let new_json = json!({
         "Id": json["Id"],
         "Name": json["Name"],
@@ -76,6 +171,32 @@ pub fn inspect(
     Ok(true)
 }
 
+/// Filter for `docker version`
+///
+/// The API versions are clamped to the ones that are allowed by the configuration.
+pub fn version(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let mut json: Value = serde_json::from_slice(&content[..])?;
+
+    for x in ["ApiVersion", "MinAPIVersion"].iter() {
+        let version = json[x].as_str().and_then(|v| config.clamp_api_version(v));
+        if let Some(version) = version {
+            json[x] = json!(version);
+        }
+    }
+
+    *content = serde_json::to_vec(&json)?;
+    Ok(true)
+}
+
 /// Filter for `docker info`
 pub fn info(
     _config: &Config,
@@ -120,3 +241,356 @@ pub fn info(
     *content = serde_json::to_vec(&new_json)?;
     Ok(true)
 }
+
+/// Filter for `docker images`
+pub fn images_list(
+    _config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let json: Value = serde_json::from_slice(&content[..])?;
+    let mut new_list = Vec::new();
+
+    if let Value::Array(ref images) = json {
+        for image in images {
+            new_list.push(json!({
+                "Id": image["Id"],
+                "RepoTags": image["RepoTags"],
+                "RepoDigests": image["RepoDigests"],
+                "Created": image["Created"],
+                "Size": image["Size"],
+            }));
+        }
+    }
+
+    *content = serde_json::to_vec(&json!(new_list))?;
+    Ok(true)
+}
+
+/// Filter for `docker image inspect <name>`
+///
+/// `ContainerConfig` and everything else that can reveal how the image was
+/// built is dropped, only white-listed variables of `Config.Env` are kept.
+pub fn image_inspect(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let json: Value = serde_json::from_slice(&content[..])?;
+    let new_env = whitelisted_env(config, &json["Config"]["Env"]);
+
+    let new_json = json!({
+        "Id": json["Id"],
+        "RepoTags": json["RepoTags"],
+        "RepoDigests": json["RepoDigests"],
+        "Created": json["Created"],
+        "Size": json["Size"],
+        "Config": {
+            "Env": new_env,
+        }
+    });
+
+    *content = serde_json::to_vec(&new_json)?;
+    Ok(true)
+}
+
+/// Filter for `docker network ls`
+pub fn networks_list(
+    _config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let json: Value = serde_json::from_slice(&content[..])?;
+    let mut new_list = Vec::new();
+
+    if let Value::Array(ref networks) = json {
+        for network in networks {
+            new_list.push(json!({
+                "Id": network["Id"],
+                "Name": network["Name"],
+                "Driver": network["Driver"],
+                "Scope": network["Scope"],
+            }));
+        }
+    }
+
+    *content = serde_json::to_vec(&json!(new_list))?;
+    Ok(true)
+}
+
+/// Filter for `docker network inspect <id>`
+///
+/// From the attached containers only their endpoints are kept. If there are scope
+/// labels, the attached containers are removed, since their labels are not known.
+pub fn network_inspect(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let json: Value = serde_json::from_slice(&content[..])?;
+    let mut new_json = json!({
+        "Id": json["Id"],
+        "Name": json["Name"],
+        "Driver": json["Driver"],
+        "Scope": json["Scope"],
+    });
+
+    if config.scope_labels().is_empty() {
+        let mut new_containers = json!({});
+        if let Value::Object(ref containers) = json["Containers"] {
+            for (id, endpoint) in containers {
+                new_containers[id] = json!({
+                    "Name": endpoint["Name"],
+                    "EndpointID": endpoint["EndpointID"],
+                    "MacAddress": endpoint["MacAddress"],
+                    "IPv4Address": endpoint["IPv4Address"],
+                    "IPv6Address": endpoint["IPv6Address"],
+                });
+            }
+        }
+        new_json["Containers"] = new_containers;
+    }
+
+    *content = serde_json::to_vec(&new_json)?;
+    Ok(true)
+}
+
+/// Filter for `docker volume ls`
+///
+/// Mount points on the host and driver options are filtered out.
+pub fn volumes_list(
+    _config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let json: Value = serde_json::from_slice(&content[..])?;
+    let mut new_list = Vec::new();
+
+    if let Value::Array(ref volumes) = json["Volumes"] {
+        for volume in volumes {
+            new_list.push(json!({
+                "Name": volume["Name"],
+                "Driver": volume["Driver"],
+                "Scope": volume["Scope"],
+            }));
+        }
+    }
+
+    *content = serde_json::to_vec(&json!({
+        "Volumes": new_list,
+        "Warnings": json["Warnings"],
+    }))?;
+    Ok(true)
+}
+
+fn filter_service(config: &Config, service: &Value) -> Value {
+    let spec = &service["Spec"];
+    let container_spec = &spec["TaskTemplate"]["ContainerSpec"];
+
+    json!({
+        "ID": service["ID"],
+        "Version": service["Version"],
+        "CreatedAt": service["CreatedAt"],
+        "UpdatedAt": service["UpdatedAt"],
+        "Spec": {
+            "Name": spec["Name"],
+            "Labels": whitelisted_labels(config, &spec["Labels"]),
+            "TaskTemplate": {
+                "ContainerSpec": {
+                    "Image": container_spec["Image"],
+                    "Labels": whitelisted_labels(config, &container_spec["Labels"]),
+                },
+                "Networks": spec["TaskTemplate"]["Networks"],
+            },
+            "Mode": spec["Mode"],
+            "EndpointSpec": spec["EndpointSpec"],
+        },
+        "Endpoint": service["Endpoint"],
+    })
+}
+
+fn filter_task(config: &Config, task: &Value) -> Value {
+    let mut new_attachments = Vec::new();
+
+    if let Value::Array(ref attachments) = task["NetworksAttachments"] {
+        for attachment in attachments {
+            new_attachments.push(json!({
+                "Network": {
+                    "ID": attachment["Network"]["ID"],
+                    "Spec": {
+                        "Name": attachment["Network"]["Spec"]["Name"],
+                    },
+                },
+                "Addresses": attachment["Addresses"],
+            }));
+        }
+    }
+
+    json!({
+        "ID": task["ID"],
+        "ServiceID": task["ServiceID"],
+        "NodeID": task["NodeID"],
+        "Slot": task["Slot"],
+        "Labels": whitelisted_labels(config, &task["Labels"]),
+        "Status": {
+            "Timestamp": task["Status"]["Timestamp"],
+            "State": task["Status"]["State"],
+            "Message": task["Status"]["Message"],
+        },
+        "DesiredState": task["DesiredState"],
+        "NetworksAttachments": new_attachments,
+    })
+}
+
+fn filter_node(config: &Config, node: &Value) -> Value {
+    json!({
+        "ID": node["ID"],
+        "Version": node["Version"],
+        "CreatedAt": node["CreatedAt"],
+        "UpdatedAt": node["UpdatedAt"],
+        "Spec": {
+            "Name": node["Spec"]["Name"],
+            "Role": node["Spec"]["Role"],
+            "Availability": node["Spec"]["Availability"],
+            "Labels": whitelisted_labels(config, &node["Spec"]["Labels"]),
+        },
+        "Description": {
+            "Hostname": node["Description"]["Hostname"],
+            "Platform": node["Description"]["Platform"],
+        },
+        "Status": node["Status"],
+        "ManagerStatus": node["ManagerStatus"],
+    })
+}
+
+/// Applies `filter_item` to every element of a JSON list.
+fn filter_swarm_list(
+    config: &Config,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+    filter_item: fn(&Config, &Value) -> Value,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let json: Value = serde_json::from_slice(&content[..])?;
+    let mut new_list = Vec::new();
+
+    if let Value::Array(ref items) = json {
+        for item in items {
+            new_list.push(filter_item(config, item));
+        }
+    }
+
+    *content = serde_json::to_vec(&json!(new_list))?;
+    Ok(true)
+}
+
+/// Applies `filter_item` to a single JSON object.
+fn filter_swarm_inspect(
+    config: &Config,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+    filter_item: fn(&Config, &Value) -> Value,
+) -> Result<bool> {
+    if res.code.unwrap_or(0) != 200 {
+        return Ok(false);
+    }
+
+    let json: Value = serde_json::from_slice(&content[..])?;
+    *content = serde_json::to_vec(&filter_item(config, &json))?;
+    Ok(true)
+}
+
+/// Filter for `docker service ls`
+///
+/// Only service names, white-listed labels, image, mode and endpoint ports are kept.
+/// Environment variables, secrets and configs references are filtered out.
+pub fn services_list(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    filter_swarm_list(config, res, content, filter_service)
+}
+
+/// Filter for `docker service inspect <id>`
+pub fn service_inspect(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    filter_swarm_inspect(config, res, content, filter_service)
+}
+
+/// Filter for `docker service ps`
+///
+/// Only task states and network attachments are kept. Task specs are filtered out.
+pub fn tasks_list(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    filter_swarm_list(config, res, content, filter_task)
+}
+
+/// Filter for `docker inspect <task id>`
+pub fn task_inspect(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    filter_swarm_inspect(config, res, content, filter_task)
+}
+
+/// Filter for `docker node ls`
+///
+/// TLS information and engine details are filtered out.
+pub fn nodes_list(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    filter_swarm_list(config, res, content, filter_node)
+}
+
+/// Filter for `docker node inspect <id>`
+pub fn node_inspect(
+    config: &Config,
+    _req: &httparse::Request,
+    res: &httparse::Response,
+    content: &mut Vec<u8>,
+) -> Result<bool> {
+    filter_swarm_inspect(config, res, content, filter_node)
+}
@@ -11,29 +11,53 @@ extern crate serde_json;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate libc;
 #[macro_use]
 extern crate clap;
 
 use std::cmp;
 use std::fs;
 use std::io;
-use std::io::{Read, Write};
+use std::mem;
+use std::io::{BufRead, Read, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::net::TcpStream;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::str;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, RwLock};
 use std::fs::File;
+use std::time::{Duration, Instant};
 
 use fs2::FileExt;
-use clap::{App, Arg, ArgMatches};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use url::Url;
+use url::form_urlencoded;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
 
 mod config;
 use config::*;
 
 mod filters;
 
+mod audit;
+use audit::*;
+
+mod metrics;
+use metrics::Metrics;
+
+mod ratelimit;
+use ratelimit::RateLimiter;
+
+mod pool;
+use pool::{ConnectionGuard, ConnectionLimiter, ThreadPool};
+
+mod reactor;
+use reactor::Reactor;
+
+mod locks;
+
+// error_chain 0.12 implements the deprecated `description` and `cause` methods
+#[allow(deprecated)]
 mod errors {
     error_chain! {
         foreign_links {
@@ -44,26 +68,92 @@ mod errors {
             SerdeJson(::serde_json::Error);
             Config(::config::config::ConfigError);
         }
+
+        errors {
+            HeadersTooLarge(limit: usize) {
+                description("HTTP headers are too large")
+                display("HTTP headers are larger than {} bytes", limit)
+            }
+            TooManyHeaders(limit: usize) {
+                description("Too many HTTP headers")
+                display("More than {} HTTP headers", limit)
+            }
+            ContentTooLarge(limit: usize) {
+                description("HTTP content is too large")
+                display("HTTP content is larger than {} bytes", limit)
+            }
+            MalformedHttp(reason: &'static str) {
+                description("Malformed HTTP message")
+                display("Malformed HTTP message: {}", reason)
+            }
+            ContentInterrupted(reason: String) {
+                description("HTTP content was interrupted after the headers were forwarded")
+                display("HTTP content was interrupted: {}", reason)
+            }
+        }
     }
 }
 
 use errors::*;
 
-trait Stream: Read + Write + AsRawFd {
-    fn as_read_mut(&mut self) -> &mut Read;
-    fn as_write_mut(&mut self) -> &mut Write;
+trait Stream: Read + Write + AsRawFd + Send {
+    fn as_read_mut(&mut self) -> &mut dyn Read;
+    fn as_write_mut(&mut self) -> &mut dyn Write;
 }
 
-impl<T> Stream for T where T: Read + Write + AsRawFd {
-    fn as_read_mut(&mut self) -> &mut Read {
+impl<T> Stream for T where T: Read + Write + AsRawFd + Send {
+    fn as_read_mut(&mut self) -> &mut dyn Read {
         self
     }
 
-    fn as_write_mut(&mut self) -> &mut Write {
+    fn as_write_mut(&mut self) -> &mut dyn Write {
         self
     }
 }
 
+/// Wraps a stream and counts the bytes that are read and written.
+struct CountingStream<S> {
+    inner: S,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl<S> CountingStream<S> {
+    fn new(inner: S) -> CountingStream<S> {
+        CountingStream {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+}
+
+impl<S: Read> Read for CountingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.bytes_read += len as u64;
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for CountingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.bytes_written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsRawFd> AsRawFd for CountingStream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
 pub enum Http<'headers, 'buf: 'headers> {
     Res(httparse::Response<'headers, 'buf>),
     Req(httparse::Request<'headers, 'buf>),
@@ -84,10 +174,10 @@ impl<'h, 'b> Http<'h, 'b> {
         }
     }
 
-    fn headers(&self) -> &[httparse::Header] {
+    fn headers(&self) -> &[httparse::Header<'_>] {
         match self {
-            Http::Res(res) => &res.headers,
-            Http::Req(req) => &req.headers,
+            Http::Res(res) => res.headers,
+            Http::Req(req) => req.headers,
         }
     }
 }
@@ -96,108 +186,402 @@ fn is_http_upgraded(http_req: &Http, http_res: &Http) -> Result<bool> {
     let http_req = http_req.req()?;
     let http_res = http_res.res()?;
 
-    Ok(find_in_headers(http_req.headers, "Connection").unwrap_or("") == "Upgrade" &&
+    Ok(header_has_token(http_req.headers, "Connection", "Upgrade") &&
        http_res.code.unwrap_or(0) == 101)
 }
 
-fn read_until(reader: &mut Read, buf: &mut Vec<u8>, until: &[u8]) -> io::Result<()> {
+/// Checks if a response can have content. Responses of `HEAD` requests, and `1xx`,
+/// `204`, and `304` responses never have one, whatever their headers say.
+fn response_has_content(head_request: bool, code: Option<u16>) -> bool {
+    match code {
+        Some(100..=199) | Some(204) | Some(304) => false,
+        _ => !head_request,
+    }
+}
+
+/// Checks if the content of the message is compressed (e.g. `Content-Encoding: gzip`).
+fn is_http_compressed(http: &Http) -> bool {
+    find_in_headers(http.headers(), "Content-Encoding").map_or(false, |x| !x.trim().eq_ignore_ascii_case("identity"))
+}
+
+/// Checks if the client connection can be used for another request after this exchange.
+fn is_http_persistent(http_req: &Http, http_res: &Http) -> Result<bool> {
+    let http_req = http_req.req()?;
+    let http_res = http_res.res()?;
+
+    // without them, the end of the response is when the connection is closed
+    let framed = find_in_headers(http_res.headers, "Content-Length").is_some() ||
+                 find_in_headers(http_res.headers, "Transfer-Encoding").is_some() ||
+                 !response_has_content(http_req.method == Some("HEAD"), http_res.code);
+
+    Ok(http_req.version == Some(1) && http_res.version == Some(1) &&
+       !header_has_token(http_req.headers, "Connection", "close") &&
+       !header_has_token(http_res.headers, "Connection", "close") && framed)
+}
+
+/// Waits until `fd` is readable. Fails with `TimedOut` if `deadline` passes.
+/// Data that is already received is not late, even if `deadline` has passed
+/// (e.g. while the connection waited for a worker).
+fn poll_readable(fd: RawFd, deadline: Instant) -> io::Result<()> {
+    loop {
+        let now = Instant::now();
+        let timeout = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+        let timeout = timeout.as_secs() * 1000 + u64::from((timeout.subsec_nanos() + 999_999) / 1_000_000);
+        let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+
+        match unsafe { libc::poll(&mut pollfd, 1, cmp::min(timeout, i32::MAX as u64) as i32) } {
+            // check again, the timeout of poll is not exact
+            0 if Instant::now() >= deadline => return Err(io::ErrorKind::TimedOut.into()),
+            0 => continue,
+            n if n > 0 => return Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Bytes that were received from a stream but are not consumed yet.
+#[derive(Default)]
+struct ReadBuffer {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadBuffer {
+    fn len(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    /// Takes the bytes that are not consumed.
+    fn take(&mut self) -> Vec<u8> {
+        let rest = self.buf.split_off(self.pos);
+        self.buf.clear();
+        self.pos = 0;
+        rest
+    }
+}
+
+/// Reads from a stream through a buffer, so an HTTP message can be parsed without
+/// losing the bytes that follow it. Fails with `TimedOut` if nothing is received
+/// before the deadline, or for longer than the idle timeout.
+struct HttpReader<'a> {
+    stream: &'a mut dyn Stream,
+    buffer: &'a mut ReadBuffer,
+    deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+}
+
+impl<'a> HttpReader<'a> {
+    fn new(stream: &'a mut dyn Stream, buffer: &'a mut ReadBuffer, deadline: Option<Instant>) -> HttpReader<'a> {
+        HttpReader { stream, buffer, deadline, idle_timeout: None }
+    }
+}
+
+impl<'a> BufRead for HttpReader<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffer.is_empty() {
+            let idle_deadline = self.idle_timeout.map(|timeout| Instant::now() + timeout);
+            let deadline = match (self.deadline, idle_deadline) {
+                (Some(deadline), Some(idle_deadline)) => Some(cmp::min(deadline, idle_deadline)),
+                (deadline, idle_deadline) => deadline.or(idle_deadline),
+            };
+            if let Some(deadline) = deadline {
+                poll_readable(self.stream.as_raw_fd(), deadline)?;
+            }
+
+            self.buffer.buf.resize(8192, 0);
+            self.buffer.pos = 0;
+            match self.stream.read(&mut self.buffer.buf) {
+                Ok(len) => self.buffer.buf.truncate(len),
+                Err(e) => {
+                    self.buffer.buf.clear();
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(&self.buffer.buf[self.buffer.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buffer.pos = cmp::min(self.buffer.pos + amt, self.buffer.buf.len());
+    }
+}
+
+impl<'a> Read for HttpReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let available = self.fill_buf()?;
+            let len = cmp::min(available.len(), buf.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            len
+        };
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+/// Checks if the error was caused by a timeout.
+fn is_timeout(err: &Error) -> bool {
+    if let ErrorKind::Io(ref e) = *err.kind() {
+        return e.kind() == io::ErrorKind::TimedOut;
+    }
+
+    match err.1.next_error {
+        Some(ref cause) => match cause.downcast_ref::<io::Error>() {
+            Some(e) => e.kind() == io::ErrorKind::TimedOut,
+            None => cause.downcast_ref::<Error>().map_or(false, is_timeout),
+        },
+        None => false,
+    }
+}
+
+/// Reads until `buf` ends with `until`. Returns `false` if `buf` reaches `limit` bytes before.
+fn read_until(reader: &mut dyn BufRead, buf: &mut Vec<u8>, until: &[u8], limit: usize) -> io::Result<bool> {
     while !buf.ends_with(until) {
-        let mut b = [0; 1];
-        let len = reader.read(&mut b)?;
-        buf.extend_from_slice(&b[..len]);
+        if buf.len() >= limit {
+            return Ok(false);
+        }
+
+        let consumed = {
+            let available = reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            // bytes after `until` must stay in the reader
+            let mut consumed = 0;
+            for b in available {
+                buf.push(*b);
+                consumed += 1;
+                if buf.ends_with(until) || buf.len() >= limit {
+                    break;
+                }
+            }
+            consumed
+        };
+        reader.consume(consumed);
     }
 
-    Ok(())
+    Ok(true)
 }
 
 fn parse_http<'b, 'h>(http: &'b [u8], headers: &'h mut [httparse::Header<'b>]) -> Result<Http<'h, 'b>> {
+    let max_headers = headers.len();
     let headers = {
         let mut res = httparse::Response::new(headers);
-        if let Ok(_) = res.parse(&http) {
-            return Ok(Http::Res(res));
+        match res.parse(http) {
+            Ok(_) => return Ok(Http::Res(res)),
+            Err(httparse::Error::TooManyHeaders) => bail!(ErrorKind::TooManyHeaders(max_headers)),
+            Err(_) => {}
         }
         res.headers
     };
 
     let mut req = httparse::Request::new(headers);
-    if let Ok(_) = req.parse(&http) {
-        return Ok(Http::Req(req));
+    match req.parse(http) {
+        Ok(_) => Ok(Http::Req(req)),
+        Err(httparse::Error::TooManyHeaders) => bail!(ErrorKind::TooManyHeaders(max_headers)),
+        Err(_) => bail!(ErrorKind::MalformedHttp("invalid headers")),
     }
-
-    Err("Failed to parse HTTP headers".into())
 }
 
+/// Returns the value of the first header with this name. Header names are case-insensitive.
 fn find_in_headers<'h>(headers: &'h [httparse::Header], name: &str) -> Option<&'h str> {
     headers
         .iter()
-        .find(|hdr| hdr.name == name)
+        .find(|hdr| hdr.name.eq_ignore_ascii_case(name))
         .and_then(|hdr| std::str::from_utf8(hdr.value).ok())
 }
 
+/// Checks if a header with a comma-separated list of tokens, e.g. `Connection`, contains `token`.
+fn header_has_token(headers: &[httparse::Header], name: &str, token: &str) -> bool {
+    find_in_headers(headers, name)
+        .map_or(false, |value| value.split(',').any(|x| x.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Checks that the end of the content is unambiguous, so docker cannot find a different
+/// end than us, and take the rest of the content as another request that was not filtered.
+fn check_http_framing(http: &Http) -> Result<()> {
+    let headers = http.headers();
+    let content_lens: Vec<_> = headers
+        .iter()
+        .filter(|hdr| hdr.name.eq_ignore_ascii_case("Content-Length"))
+        .collect();
+    let transfer_encodings: Vec<_> = headers
+        .iter()
+        .filter(|hdr| hdr.name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .collect();
+
+    if !content_lens.is_empty() && !transfer_encodings.is_empty() {
+        bail!(ErrorKind::MalformedHttp("both Content-Length and Transfer-Encoding"));
+    }
+    if content_lens.len() > 1 {
+        bail!(ErrorKind::MalformedHttp("multiple Content-Length headers"));
+    }
+    if transfer_encodings.len() > 1 {
+        bail!(ErrorKind::MalformedHttp("multiple Transfer-Encoding headers"));
+    }
+
+    if let Some(hdr) = content_lens.first() {
+        if hdr.value.is_empty() || !hdr.value.iter().all(u8::is_ascii_digit) ||
+           str::from_utf8(hdr.value)?.parse::<usize>().is_err() {
+            bail!(ErrorKind::MalformedHttp("invalid Content-Length"));
+        }
+    }
+    if let Some(hdr) = transfer_encodings.first() {
+        if !str::from_utf8(hdr.value)?.trim().eq_ignore_ascii_case("chunked") {
+            bail!(ErrorKind::MalformedHttp("unsupported Transfer-Encoding"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads and parses the headers of an HTTP message. The number of headers is
+/// limited by the length of `headers`.
 fn read_http_headers<'h, 'b>(
-    reader: &mut Read,
+    reader: &mut dyn BufRead,
     headers: &'h mut [httparse::Header<'b>],
     hdr_buf: &'b mut Vec<u8>,
+    max_len: usize,
 ) -> Result<Http<'h, 'b>> {
-    read_until(reader, hdr_buf, b"\r\n\r\n").chain_err(|| "Failed to read http header")?;
-    parse_http(hdr_buf, headers)
-}
+    if !read_until(reader, hdr_buf, b"\r\n\r\n", max_len).chain_err(|| "Failed to read http header")? {
+        bail!(ErrorKind::HeadersTooLarge(max_len));
+    }
 
-fn read_http_content(reader: &mut Read, http: &Http) -> Result<Vec<u8>> {
-    let mut content_buf = Vec::new();
-    let headers = http.headers();
+    // obs-fold continues a header in the next line, which is obsolete and parsed
+    // differently by each implementation
+    if hdr_buf.windows(3).any(|x| x == b"\r\n " || x == b"\r\n\t") {
+        bail!(ErrorKind::MalformedHttp("obsolete line folding"));
+    }
 
-    if let Some(transfer_encoding) = find_in_headers(headers, "Transfer-Encoding") {
-        if transfer_encoding == "chunked" {
-            // read all chunks
-            loop {
-                // read chunked length
-                let mut buf = Vec::new();
-                read_until(reader, &mut buf, b"\r\n")?;
-
-                let chunked_len = str::from_utf8(&buf)?;
-                let chunked_len = usize::from_str_radix(&chunked_len.trim(), 16)?;
-
-                // read chunk
-                let mut read_chunked_len = 0;
-                while read_chunked_len < chunked_len {
-                    let mut buf = [0; 4096];
-                    let read_len = cmp::min(chunked_len - read_chunked_len, buf.len());
-                    let len = reader.read(&mut buf[..read_len])?;
-                    content_buf.extend_from_slice(&buf[..len]);
-                    read_chunked_len += len;
-                }
+    let http = parse_http(hdr_buf, headers)?;
+    check_http_framing(&http)?;
+    Ok(http)
+}
 
-                // read CRLF
-                let mut buf = [0; 2];
-                reader.read_exact(&mut buf)?;
-                if buf.ne(b"\r\n") {
-                    return Err("Malformed chunked encoding".into());
-                }
+/// Reads a chunk of chunked content and appends it to `content_buf`. Returns `false`
+/// if it was the last (i.e. zero-length) chunk. If `content_buf` exceeds `max_len`
+/// it is an error.
+fn read_http_chunk(reader: &mut dyn BufRead, content_buf: &mut Vec<u8>, max_len: usize) -> Result<bool> {
+    // read chunked length
+    let mut buf = Vec::new();
+    if !read_until(reader, &mut buf, b"\r\n", 1024)? {
+        bail!(ErrorKind::MalformedHttp("invalid chunk size"));
+    }
 
-                // stop if this was the last chunk (i.e. zero-length chunk)
-                if chunked_len == 0 {
-                    break;
-                }
-            }
-        } else {
-            return Err(format!("Transfer-Encoding `{}` is not supported", transfer_encoding).into());
+    // ignore chunk extensions
+    let chunked_len = buf[..buf.len() - 2].split(|x| *x == b';').next().unwrap_or(b"");
+    if chunked_len.is_empty() || !chunked_len.iter().all(u8::is_ascii_hexdigit) {
+        bail!(ErrorKind::MalformedHttp("invalid chunk size"));
+    }
+    let chunked_len = usize::from_str_radix(str::from_utf8(chunked_len)?, 16)
+        .chain_err(|| ErrorKind::MalformedHttp("invalid chunk size"))?;
+    if chunked_len > max_len - content_buf.len() {
+        bail!(ErrorKind::ContentTooLarge(max_len));
+    }
+
+    // read chunk
+    let mut read_chunked_len = 0;
+    while read_chunked_len < chunked_len {
+        let mut buf = [0; 4096];
+        let read_len = cmp::min(chunked_len - read_chunked_len, buf.len());
+        let len = reader.read(&mut buf[..read_len])?;
+        if len == 0 {
+            return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
         }
+        content_buf.extend_from_slice(&buf[..len]);
+        read_chunked_len += len;
+    }
+
+    // read CRLF
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    if buf.ne(b"\r\n") {
+        bail!(ErrorKind::MalformedHttp("invalid chunk"));
+    }
+
+    Ok(chunked_len > 0)
+}
+
+/// Reads the content of an HTTP message. If `max_len` is given, larger content is
+/// an error.
+fn read_http_content(reader: &mut dyn BufRead, http: &Http, max_len: Option<usize>) -> Result<Vec<u8>> {
+    let max_len = max_len.unwrap_or(usize::MAX);
+    let mut content_buf = Vec::new();
+    let headers = http.headers();
+
+    // framing is already checked by `check_http_framing`
+    if find_in_headers(headers, "Transfer-Encoding").is_some() {
+        // read all chunks
+        while read_http_chunk(reader, &mut content_buf, max_len)? {}
     } else if let Some(content_len) = find_in_headers(headers, "Content-Length") {
         if let Ok(mut content_len) = content_len.parse::<usize>() {
+            if content_len > max_len {
+                bail!(ErrorKind::ContentTooLarge(max_len));
+            }
             while content_len > 0 {
                 let mut buf = [0; 4096];
-                let len = reader.read(&mut buf)?;
+                let len = reader.read(&mut buf[..cmp::min(content_len, 4096)])?;
+                if len == 0 {
+                    return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                }
                 content_buf.extend_from_slice(&buf[..len]);
                 content_len -= len;
             }
         }
+    } else if let Http::Res(_) = http {
+        // without framing headers the content of a response ends when the connection is closed
+        loop {
+            let mut buf = [0; 4096];
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            if len > max_len - content_buf.len() {
+                bail!(ErrorKind::ContentTooLarge(max_len));
+            }
+            content_buf.extend_from_slice(&buf[..len]);
+        }
     }
 
     Ok(content_buf)
 }
 
-fn write_http_headers(writer: &mut Write, http: &Http, content_len: Option<usize>) -> Result<()> {
+/// Changes that are applied to the headers of a request or response before
+/// they are forwarded.
+#[derive(Default)]
+struct HttpRewrite {
+    /// Replaces the path of the request.
+    path: Option<String>,
+    /// Replace the headers with the same name, or are added if they do not exist.
+    headers: Vec<(String, String)>,
+    /// Names of the headers that are removed.
+    remove_headers: Vec<String>,
+}
+
+/// Framing of the content that follows the headers.
+#[derive(Clone, Copy)]
+enum Content {
+    Length(usize),
+    Chunked,
+    /// Message cannot have content, so its `Content-Length` and `Transfer-Encoding`
+    /// headers are forwarded as they are (e.g. in the response of a `HEAD` request).
+    None,
+    /// Content ends when the connection is closed, so no framing headers are sent.
+    Close,
+}
+
+fn write_http_headers(writer: &mut dyn Write, http: &Http, rewrite: &HttpRewrite, content: Content) -> Result<()> {
     match http {
         Http::Res(res) => {
             let version = res.version.unwrap_or(0);
@@ -207,7 +591,7 @@ fn write_http_headers(writer: &mut Write, http: &Http, content_len: Option<usize
         }
         Http::Req(req) => {
             let method = req.method.ok_or("Undefined method")?;
-            let path = req.path.unwrap_or("/");
+            let path = rewrite.path.as_deref().or(req.path).unwrap_or("/");
             let version = req.version.unwrap_or(0);
             writer.write_all(format!("{} {} HTTP/1.{}\r\n", method, path, version).as_bytes())?;
         }
@@ -215,7 +599,13 @@ fn write_http_headers(writer: &mut Write, http: &Http, content_len: Option<usize
 
     for hdr in http.headers() {
         // strip content length and transfer encoding since they could been changed, we add them later
-        if hdr.name == "Content-Length" || hdr.name == "Transfer-Encoding" {
+        let framing = hdr.name.eq_ignore_ascii_case("Content-Length") ||
+                      hdr.name.eq_ignore_ascii_case("Transfer-Encoding");
+        if framing && !matches!(content, Content::None) {
+            continue;
+        }
+        if rewrite.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case(hdr.name)) ||
+           rewrite.remove_headers.iter().any(|name| name.eq_ignore_ascii_case(hdr.name)) {
             continue;
         }
         writer.write_all(format!("{}: ", hdr.name).as_bytes())?;
@@ -223,117 +613,166 @@ fn write_http_headers(writer: &mut Write, http: &Http, content_len: Option<usize
         writer.write_all(b"\r\n")?;
     }
 
-    match content_len {
-        Some(len) => {
-            if len > 0 {
+    for (name, value) in &rewrite.headers {
+        writer.write_all(format!("{}: {}\r\n", name, value).as_bytes())?;
+    }
+
+    match content {
+        Content::Length(len) => {
+            if len > 0 || find_in_headers(http.headers(), "Content-Length").is_some() {
                 writer.write_all(format!("Content-Length: {}\r\n", len).as_bytes())?;
             }
         }
-        None => writer.write_all(b"Transfer-Encoding: chunked\r\n")?,
+        Content::Chunked => writer.write_all(b"Transfer-Encoding: chunked\r\n")?,
+        Content::None | Content::Close => {}
     }
 
     writer.write_all(b"\r\n")?;
     Ok(())
 }
 
-fn write_http_content(writer: &mut Write, content: &[u8]) -> Result<()> {
+/// Writes a docker-style error response, i.e. the error message in a JSON object.
+fn write_http_error(writer: &mut dyn Write, code: u16, reason: &str, message: &str) -> Result<()> {
+    let content = serde_json::to_vec(&json!({ "message": message }))?;
+    writer.write_all(format!("HTTP/1.1 {} {}\r\n", code, reason).as_bytes())?;
+    writer.write_all(b"Content-Type: application/json\r\n")?;
+    writer.write_all(b"Connection: close\r\n")?;
+    writer.write_all(format!("Content-Length: {}\r\n\r\n", content.len()).as_bytes())?;
+    writer.write_all(&content)?;
+    Ok(())
+}
+
+fn write_http_content(writer: &mut dyn Write, content: &[u8]) -> Result<()> {
     writer.write_all(content)?;
     Ok(())
 }
 
-fn write_http_content_chunked(writer: &mut Write, content: &[u8]) -> Result<()> {
-    if content.len() > 0 {
-        writer.write_all(format!("{:x}\r\n", content.len()).as_bytes())?;
-        writer.write_all(content)?;
+/// Writes a chunk of chunked content. Empty chunks are skipped, since they mark
+/// the end of the content.
+fn write_http_chunk(writer: &mut dyn Write, chunk: &[u8]) -> Result<()> {
+    if !chunk.is_empty() {
+        writer.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())?;
+        writer.write_all(chunk)?;
         writer.write_all(b"\r\n")?;
     }
-    writer.write_all(b"0\r\n\r\n")?;
     Ok(())
 }
 
-fn forward_data(from: &mut Read, to: &mut Write) -> io::Result<usize> {
-    let mut buf = [0; 1024];
-    let len = from.read(&mut buf)?;
-    to.write_all(&buf[..len])?;
-    Ok(len)
+/// Limits and options for forwarding an HTTP message.
+#[derive(Clone, Copy)]
+struct ReadLimits {
+    /// Deadline for receiving the headers.
+    headers_deadline: Option<Instant>,
+    /// Time limit for receiving the content, after the headers are received.
+    content_timeout: Option<Duration>,
+    /// Time limit for each read of the content, so the other end cannot stall it.
+    content_idle_timeout: Option<Duration>,
+    max_header_bytes: usize,
+    /// If `None`, the content is not limited, and chunked content is forwarded as
+    /// it arrives, without being filtered.
+    max_content_len: Option<usize>,
+    /// The message is the response of a `HEAD` request.
+    head_response: bool,
+    /// The content can be forwarded in chunks. HTTP/1.0 clients do not support them, so
+    /// unlimited chunked content is forwarded to them until the connection is closed.
+    chunked: bool,
+}
+
+/// Returns `false` if `http` is a response that cannot have content.
+fn http_has_content(http: &Http, limits: &ReadLimits) -> bool {
+    match http {
+        Http::Res(res) => response_has_content(limits.head_response, res.code),
+        Http::Req(_) => true,
+    }
 }
 
 fn forward_http<'h, 'b: 'h, FH, FC>(
-    from: &mut Read,
-    to: &mut Write,
+    from: &mut HttpReader,
+    to: &mut dyn Write,
     hdr_buf: &'b mut Vec<u8>,
     headers: &'h mut [httparse::Header<'b>],
+    limits: ReadLimits,
     filter_headers: FH,
     filter_content: FC,
 ) -> Result<Option<Http<'h, 'b>>>
 where
-    FH: FnOnce(&Http<'h, 'b>) -> Result<bool>,
+    FH: FnOnce(&Http<'h, 'b>) -> Result<Option<HttpRewrite>>,
     FC: FnOnce(&Http<'h, 'b>, &mut Vec<u8>) -> Result<bool>,
 {
-    let http = read_http_headers(from, headers, hdr_buf)?;
-
-    if !filter_headers(&http)? {
-        return Ok(None);
-    }
+    from.deadline = limits.headers_deadline;
+    let http = read_http_headers(from, headers, hdr_buf, limits.max_header_bytes)?;
+    from.deadline = limits.content_timeout.map(|timeout| Instant::now() + timeout);
+    from.idle_timeout = limits.content_idle_timeout;
+
+    // `None` means that http was filtered out
+    let rewrite = match filter_headers(&http)? {
+        Some(rewrite) => rewrite,
+        None => return Ok(None),
+    };
 
-    if find_in_headers(http.headers(), "Transfer-Encoding").unwrap_or("") == "chunked" {
+    if !http_has_content(&http, &limits) {
+        write_http_headers(to, &http, &rewrite, Content::None)?;
+    } else if find_in_headers(http.headers(), "Transfer-Encoding").is_some() && limits.max_content_len.is_none() {
         // in case of `chunked` transfer encoding we forward the headers before we try
         // to receive the content.
         // we do this because the content can be available after a lot of time (even minutes),
         // however we need to inform the other end that we received the headers of request/response.
-        write_http_headers(to, &http, None)?;
-        let mut content_buf = read_http_content(from, &http)?;
-        if !filter_content(&http, &mut content_buf)? {
-            return Ok(None);
-        }
-        write_http_content_chunked(to, &content_buf)?;
+        // limited content is not forwarded like this, so an error can be sent if it is too large.
+        // HTTP/1.0 clients receive the content without chunks, until the connection is closed.
+        let content = if limits.chunked { Content::Chunked } else { Content::Close };
+        write_http_headers(to, &http, &rewrite, content)?;
+        // chunks are forwarded as they arrive, e.g. progress of long running operations
+        let mut forward_chunks = || -> Result<()> {
+            let mut chunk = Vec::new();
+            while read_http_chunk(from, &mut chunk, usize::MAX)? {
+                if limits.chunked {
+                    write_http_chunk(to, &chunk)?;
+                } else {
+                    to.write_all(&chunk)?;
+                }
+                to.flush()?;
+                chunk.clear();
+            }
+            if limits.chunked {
+                to.write_all(b"0\r\n\r\n")?;
+            }
+            Ok(())
+        };
+        // the headers are already forwarded, so the error cannot be reported to the other end
+        forward_chunks().map_err(|err| ErrorKind::ContentInterrupted(err.to_string()))?;
     } else {
-        let mut content_buf = read_http_content(from, &http)?;
+        let mut content_buf = read_http_content(from, &http, limits.max_content_len)?;
         if !filter_content(&http, &mut content_buf)? {
             return Ok(None);
         }
-        write_http_headers(to, &http, Some(content_buf.len()))?;
+        write_http_headers(to, &http, &rewrite, Content::Length(content_buf.len()))?;
         write_http_content(to, &content_buf)?;
     }
 
     Ok(Some(http))
 }
 
-fn handle_upgraded(stream1: &mut Stream, stream2: &mut Stream) -> Result<()> {
-    let epfd = epoll::create(true)?;
-
-    let ev = epoll::Event::new(epoll::Events::EPOLLIN, stream1.as_raw_fd() as u64);
-    epoll::ctl(epfd, epoll::ControlOptions::EPOLL_CTL_ADD, stream1.as_raw_fd(), ev)?;
-
-    let ev = epoll::Event::new(epoll::Events::EPOLLIN, stream2.as_raw_fd() as u64);
-    epoll::ctl(epfd, epoll::ControlOptions::EPOLL_CTL_ADD, stream2.as_raw_fd(), ev)?;
-
-    'outer: loop {
-        let mut events = [epoll::Event::new(epoll::Events::EPOLLIN, 0); 2];
-        let num_of_events = epoll::wait(epfd, -1, &mut events)?;
-
-        for ev in events[..num_of_events].iter() {
-            if ev.events & epoll::Events::EPOLLHUP.bits() != 0 {
-                break 'outer;
-            }
+/// Returns the credentials of the process that is connected to the other end of `stream`.
+fn peer_cred(stream: &UnixStream) -> io::Result<libc::ucred> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void,
+                         &mut len)
+    };
 
-            let fd = ev.data as RawFd;
-            if fd == stream1.as_raw_fd() {
-                if forward_data(stream1.as_read_mut(), stream2.as_write_mut())? == 0 {
-                    break 'outer;
-                }
-            } else if fd == stream2.as_raw_fd() {
-                if forward_data(stream2.as_read_mut(), stream1.as_write_mut())? == 0 {
-                    break 'outer;
-                }
-            }
-        }
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
     }
 
-    Ok(())
+    Ok(cred)
 }
 
-fn connect_to_docker(url: &Url) -> Result<Box<Stream>> {
+fn connect_to_docker(url: &Url, timeout: Option<Duration>) -> Result<Box<dyn Stream>> {
     match url.scheme() {
         "unix" => {
             let path = match url.path() {
@@ -347,99 +786,806 @@ fn connect_to_docker(url: &Url) -> Result<Box<Stream>> {
                 "" => "127.0.0.1",
                 host => host,
             };
-            Ok(Box::new(TcpStream::connect(format!("{}:{}", host, url.port().unwrap_or(2375)))?))
+            let addr = format!("{}:{}", host, url.port().unwrap_or(2375));
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return Ok(Box::new(TcpStream::connect(addr)?)),
+            };
+
+            let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "Docker host has no address");
+            for addr in addr.to_socket_addrs()? {
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(stream) => return Ok(Box::new(stream)),
+                    Err(e) => last_err = e,
+                }
+            }
+            Err(last_err.into())
         }
         _ => Err("Unsupported docker host uri".into())
     }
 }
 
-fn handle_client(stream: &mut UnixStream, config: Arc<Config>) -> Result<()> {
-    let mut fwd = connect_to_docker(&config.docker_host)?;
+/// Normalizes the target of a request before it is matched, so it is routed by
+/// docker the same way: absolute-form targets are reduced to the path and query,
+/// the path is percent-decoded, empty and dot segments are removed, and both the
+/// path and the query are encoded again in a single way.
+///
+/// On error it returns the message that must be sent to the client.
+fn normalize_request_target(target: &str) -> ::std::result::Result<String, String> {
+    if target.contains('#') {
+        return Err("request target must not have a fragment".to_owned());
+    }
+
+    // the authority of absolute-form targets is ignored. They are not parsed as URLs,
+    // since URL parsers fix some invalid paths (e.g. they replace `\` with `/`).
+    let mut target = target;
+    if !target.starts_with('/') {
+        let lowercase = target.to_ascii_lowercase();
+        let rest = ["http://", "https://"].iter()
+            .find(|scheme| lowercase.starts_with(*scheme))
+            .map(|scheme| &target[scheme.len()..])
+            .filter(|rest| !rest.is_empty() && !rest.starts_with(|c| c == '/' || c == '?'))
+            .ok_or_else(|| format!("invalid request target: {}", target))?;
+        target = match rest.find(|c| c == '/' || c == '?') {
+            Some(i) => &rest[i..],
+            None => "/",
+        };
+    }
+
+    let mut parts = target.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next().unwrap_or("");
+
+    let mut segments: Vec<String> = Vec::new();
+    for segment in path.split('/') {
+        let segment = percent_decode(segment.as_bytes()).decode_utf8()
+            .map_err(|_| "request path is not valid UTF-8".to_owned())?;
+
+        if segment.contains('/') {
+            return Err("request path must not have encoded slashes".to_owned());
+        }
+        if segment.chars().any(|c| c.is_control()) {
+            return Err("request path must not have control characters".to_owned());
+        }
+
+        match segment.as_ref() {
+            "" | "." => {}
+            ".." => { segments.pop(); }
+            segment => segments.push(utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string()),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+
+    let mut pairs = form_urlencoded::Serializer::new(String::new());
+    let mut has_pairs = false;
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        pairs.append_pair(&name, &value);
+        has_pairs = true;
+    }
+    if has_pairs {
+        normalized.push('?');
+        normalized.push_str(&pairs.finish());
+    }
+
+    Ok(normalized)
+}
+
+/// Checks if request is allowed and retrieves the filter function for the response content.
+/// Returns `None` if request is denied. If the client must be informed about the denial,
+/// `http_error` is set to the status code and the message of the error response.
+///
+/// Rate limits are checked only if `rate_limiter` is given.
+fn filter_request(
+    config: &Config,
+    rate_limiter: Option<&RateLimiter>,
+    http_req: &Http,
+    filter_fn: &mut Option<FilterFn>,
+    http_error: &mut Option<(u16, &'static str, String)>,
+    record: &mut AuditRecord,
+) -> Result<Option<HttpRewrite>> {
+    let req = http_req.req().chain_err(|| "HTTP request was expected")?;
+    let method = req.method.unwrap_or("UNKNOWN");
+    let mut path = req.path.unwrap_or("/").to_owned();
+    let mut rewrite = HttpRewrite::default();
+
+    record.method = Some(method.to_owned());
+    record.path = Some(path.clone());
+
+    match normalize_request_target(&path) {
+        Ok(ref normalized) if *normalized == path => {}
+        Ok(normalized) => {
+            debug!("Normalize: {} -> {}", path, normalized);
+            path = normalized;
+            rewrite.path = Some(path.clone());
+        }
+        Err(msg) => {
+            info!("Deny:  {} {} ({})", method, path, msg);
+            *http_error = Some((400, "Bad Request", msg));
+            return Ok(None);
+        }
+    }
+
+    match config.check_api_version(&path) {
+        Ok(Some(pinned_path)) => {
+            debug!("Pin: {} -> {}", path, pinned_path);
+            path = pinned_path;
+            rewrite.path = Some(path.clone());
+        }
+        Ok(None) => {}
+        Err(msg) => {
+            info!("Deny:  {} {} ({})", method, path, msg);
+            *http_error = Some((400, "Bad Request", msg));
+            return Ok(None);
+        }
+    }
+
+    let rule = match config.match_http_path(method, &path) {
+        Some(rule) => {
+            record.rule = Some(rule.name);
+
+            if let Some(rate_limiter) = rate_limiter {
+                if !rate_limiter.acquire(config, record.uid, rule.name) {
+                    info!("Deny:  {} {} (rate limit of `{}` rule exceeded)", method, path, rule.name);
+                    *http_error = Some((429, "Too Many Requests", "rate limit exceeded".to_owned()));
+                    return Ok(None);
+                }
+            }
+
+            let mut parts = path.splitn(2, '?');
+            let rule_path = parts.next().unwrap_or("").to_owned();
+            let query = parts.next().unwrap_or("");
+            let filtered_query = match rule.filter_query(query) {
+                Ok(filtered_query) => filtered_query,
+                Err(msg) => {
+                    info!("Deny:  {} {} ({})", method, path, msg);
+                    *http_error = Some((400, "Bad Request", msg));
+                    return Ok(None);
+                }
+            };
+            if filtered_query != query {
+                let new_path = if filtered_query.is_empty() {
+                    rule_path
+                } else {
+                    format!("{}?{}", rule_path, filtered_query)
+                };
+                debug!("Filter query: {} -> {}", path, new_path);
+                path = new_path;
+                rewrite.path = Some(path.clone());
+            }
+
+            rule
+        }
+        None => {
+            info!("Deny:  {} {}", method, path);
+            return Ok(None);
+        }
+    };
+
+    if let Some(rewrite_fn) = config.match_http_rewrite(&path) {
+        match rewrite_fn(config, &path) {
+            Ok(new_path) => {
+                debug!("Rewrite: {} -> {}", path, new_path);
+                rewrite.path = Some(new_path);
+            }
+            Err(msg) => {
+                info!("Deny:  {} {} ({})", method, path, msg);
+                *http_error = Some((400, "Bad Request", msg));
+                return Ok(None);
+            }
+        }
+    }
+
+    *filter_fn = rule.filter;
+    record.allowed = true;
+    info!("Allow: {} {}", method, path);
+
+    rewrite.remove_headers = config.headers.request_remove.clone();
+    rewrite.headers = config.headers.request_add.clone();
+
+    // filters can read only uncompressed content
+    if filter_fn.is_some() {
+        rewrite.remove_headers.push("Accept-Encoding".to_owned());
+    }
+
+    Ok(Some(rewrite))
+}
+
+/// Ensures that we received a response and clamps the API version that
+/// docker advertises, so clients do not negotiate a version that we reject.
+/// The configured headers are removed or added.
+fn filter_response(config: &Config, http_res: &Http) -> Result<Option<HttpRewrite>> {
+    http_res.res().chain_err(|| "HTTP response was expected")?;
+    let mut rewrite = HttpRewrite {
+        headers: config.headers.response_add.clone(),
+        remove_headers: config.headers.response_remove.clone(),
+        ..HttpRewrite::default()
+    };
+
+    if let Some(version) = find_in_headers(http_res.headers(), "Api-Version") {
+        if let Some(version) = config.clamp_api_version(version) {
+            rewrite.headers.push(("Api-Version".to_owned(), version));
+        }
+    }
+
+    Ok(Some(rewrite))
+}
+
+/// What happens with a client connection after a request is handled.
+enum Outcome {
+    Close,
+    KeepAlive,
+    /// Connection is upgraded and must be forwarded to the upstream connection,
+    /// after the bytes that were already received from it.
+    Upgrade(Box<dyn Stream>, Vec<u8>),
+}
+
+/// Reads the headers of a request and responds with an error. The request is read
+/// first, otherwise the client may get a connection reset instead of the error.
+fn reject_request(
+    reader: &mut HttpReader,
+    limits: &Limits,
+    record: &mut AuditRecord,
+    code: u16,
+    reason: &str,
+    message: &str,
+) -> Result<()> {
+    record.status = Some(code);
+    record.error = Some(message.to_owned());
+
+    {
+        let mut hdr_buf = Vec::new();
+        let mut headers = vec![httparse::EMPTY_HEADER; limits.headers];
+        let http_req = read_http_headers(reader, &mut headers, &mut hdr_buf, limits.header_bytes)?;
+        let req = http_req.req()?;
+        record.method = req.method.map(|x| x.to_owned());
+        record.path = req.path.map(|x| x.to_owned());
+    }
+
+    write_http_error(reader.stream.as_write_mut(), code, reason, message)
+}
+
+fn handle_client(
+    stream: &mut CountingStream<UnixStream>,
+    buffer: &mut ReadBuffer,
+    config: &Config,
+    metrics: &Metrics,
+    rate_limiter: &RateLimiter,
+    record: &mut AuditRecord,
+    headers_deadline: Option<Instant>,
+) -> Result<Outcome> {
+    let mut fwd = match connect_to_docker(&config.docker_host, config.timeouts.connect) {
+        Ok(fwd) => fwd,
+        Err(ref err) if is_timeout(err) => {
+            warn!("Timed out while connecting to docker");
+            let mut reader = HttpReader::new(stream, buffer, headers_deadline);
+            reject_request(&mut reader, &config.limits, record, 504, "Gateway Timeout",
+                           "docker did not accept the connection in time")?;
+            return Ok(Outcome::Close);
+        }
+        Err(err) => return Err(err),
+    };
     let mut filter_fn: Option<FilterFn> = None;
+    let mut http_error = None;
 
     // receive request for our sock and send it to the docker sock.
     let mut hdr_buf = Vec::new();
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let http_req = forward_http(stream, fwd.as_write_mut(), &mut hdr_buf, &mut headers,
-                                // check if request path is allowed and retrieve the filter
-                                // function for the response content.
-                                |http_req| {
-                                    let req = http_req.req().chain_err(|| "HTTP request was expected")?;
-                                    let method = req.method.unwrap_or("UNKNOWN");
-                                    let path = req.path.unwrap_or("/");
-                                    match config.match_http_path(path) {
-                                        Some(func) => {
-                                            filter_fn = func;
-                                            info!("Allow: {} {}", method, path);
-                                            Ok(true)
-                                        }
-                                        None => {
-                                            info!("Deny:  {} {}", method, path);
-                                            Ok(false)
-                                        }
-                                    }
-                                },
+    let mut headers = vec![httparse::EMPTY_HEADER; config.limits.headers];
+    let limits = ReadLimits {
+        headers_deadline,
+        content_timeout: config.timeouts.request_body,
+        content_idle_timeout: None,
+        max_header_bytes: config.limits.header_bytes,
+        max_content_len: Some(config.limits.request_body),
+        head_response: false,
+        chunked: true,
+    };
+    let http_req = forward_http(&mut HttpReader::new(stream, buffer, None), fwd.as_write_mut(),
+                                &mut hdr_buf, &mut headers, limits,
+                                |http_req| filter_request(config, Some(rate_limiter), http_req,
+                                                          &mut filter_fn, &mut http_error, record),
                                 // for now we do not support filtering of request content
-                                |_, _| Ok(true))?;
+                                |_, _| Ok(true));
+    let http_req = match http_req {
+        Ok(http_req) => http_req,
+        Err(err) => {
+            let (code, reason, msg) = match *err.kind() {
+                ErrorKind::HeadersTooLarge(_) | ErrorKind::TooManyHeaders(_) =>
+                    (431, "Request Header Fields Too Large", "request headers are too large"),
+                ErrorKind::ContentTooLarge(_) => (413, "Payload Too Large", "request body is too large"),
+                ErrorKind::MalformedHttp(_) => (400, "Bad Request", "malformed request"),
+                _ if is_timeout(&err) => (408, "Request Timeout", "request was not received in time"),
+                _ => return Err(err),
+            };
+            info!("Rejecting request: {}", err);
+            record.status = Some(code);
+            record.error = Some(msg.to_owned());
+            write_http_error(stream, code, reason, msg)?;
+            return Ok(Outcome::Close);
+        }
+    };
     // if http_req is None, then http request was filtered out
     let http_req = match http_req {
         Some(v) => v,
-        None => return Ok(()),
+        None => {
+            if let Some((code, reason, msg)) = http_error {
+                record.status = Some(code);
+                write_http_error(stream, code, reason, &msg)?;
+            }
+            return Ok(Outcome::Close);
+        }
     };
 
     // receive response from docker sock and send it to our sock.
+    let upstream_start = Instant::now();
     let mut hdr_buf = Vec::new();
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let http_res = forward_http(fwd.as_read_mut(), stream, &mut hdr_buf, &mut headers,
-                                // ensure that we received a response
-                                |http_res| http_res
-                                                .res()
-                                                .map(|_| Ok(true))
-                                                .chain_err(|| "HTTP response was expected")?,
+    let mut headers = vec![httparse::EMPTY_HEADER; config.limits.headers];
+    let limits = ReadLimits {
+        headers_deadline: config.timeouts.response_headers.map(|timeout| upstream_start + timeout),
+        // streamed content can take any time, but docker must not stall it
+        content_timeout: None,
+        content_idle_timeout: config.timeouts.response_idle,
+        max_header_bytes: config.limits.header_bytes,
+        // only content that is filtered must be kept in memory
+        max_content_len: filter_fn.map(|_| config.limits.response_body),
+        head_response: http_req.req()?.method == Some("HEAD"),
+        chunked: http_req.req()?.version == Some(1),
+    };
+    let mut fwd_buffer = ReadBuffer::default();
+    let rule = record.rule;
+    let http_res = forward_http(&mut HttpReader::new(&mut *fwd, &mut fwd_buffer, None), stream,
+                                &mut hdr_buf, &mut headers, limits,
+                                |http_res| {
+                                    // the content can be streamed for a long time, so it is not included
+                                    metrics.upstream_latency(rule, upstream_start.elapsed());
+                                    filter_response(config, http_res)
+                                },
                                 // filter content if needed
                                 |http_res, content| {
+                                    if filter_fn.is_some() && is_http_compressed(http_res) {
+                                        bail!(ErrorKind::MalformedHttp("compressed content cannot be filtered"));
+                                    }
                                     match filter_fn {
-                                        Some(filter_fn) => filter_fn(&config,
+                                        Some(filter_fn) => filter_fn(config,
                                                                      http_req.req().unwrap(),
                                                                      http_res.res().unwrap(),
-                                                                     content),
+                                                                     content)
+                                            .map_err(|err| {
+                                                metrics.filter_error(rule);
+                                                err
+                                            }),
                                         None => Ok(true),
                                     }
-                                })?;
+                                });
+    let http_res = match http_res {
+        Ok(http_res) => http_res,
+        Err(Error(ErrorKind::ContentInterrupted(reason), _)) => {
+            warn!("Failed to forward the response of docker: {}", reason);
+            record.error = Some("response of docker was interrupted".to_owned());
+            return Ok(Outcome::Close);
+        }
+        Err(err) => {
+            let (code, reason, msg) = match *err.kind() {
+                ErrorKind::HeadersTooLarge(_) | ErrorKind::TooManyHeaders(_) | ErrorKind::ContentTooLarge(_) =>
+                    (502, "Bad Gateway", "response of docker is too large"),
+                ErrorKind::MalformedHttp(_) => (502, "Bad Gateway", "malformed response of docker"),
+                _ if is_timeout(&err) => (504, "Gateway Timeout", "docker did not respond in time"),
+                _ => return Err(err),
+            };
+            warn!("Failed to receive the response of docker: {}", err);
+            record.status = Some(code);
+            record.error = Some(msg.to_owned());
+            write_http_error(stream, code, reason, msg)?;
+            return Ok(Outcome::Close);
+        }
+    };
     // if http_res is None, then http response was filtered out
     let http_res = match http_res {
         Some(v) => v,
-        None => return Ok(()),
+        None => return Ok(Outcome::Close),
     };
+    record.status = http_res.res()?.code;
 
     if is_http_upgraded(&http_req, &http_res)? {
-        handle_upgraded(stream, &mut *fwd)?;
+        Ok(Outcome::Upgrade(fwd, fwd_buffer.take()))
+    } else if is_http_persistent(&http_req, &http_res)? {
+        Ok(Outcome::KeepAlive)
+    } else {
+        Ok(Outcome::Close)
+    }
+}
+
+/// Creates the audit record of a new connection.
+fn new_audit_record(stream: &UnixStream) -> AuditRecord {
+    let mut record = AuditRecord::new();
+
+    match peer_cred(stream) {
+        Ok(cred) => {
+            record.uid = Some(cred.uid);
+            record.pid = Some(cred.pid);
+        }
+        Err(e) => warn!("Failed to get credentials of client: {}", e),
     }
 
-    Ok(())
+    record
 }
 
-fn run(arg_matches: ArgMatches) -> Result<()> {
-    let mut config = Arc::new(Config::from_arg_matches(arg_matches)?);
+/// Rejects the connections that exceed the connection limits. This runs in its own
+/// thread, so slow clients do not block the accept loop.
+fn reject_connections(
+    receiver: mpsc::Receiver<(UnixStream, AuditRecord)>,
+    audit_log: Option<Arc<AuditLog>>,
+    metrics: Arc<Metrics>,
+) {
+    for (mut stream, mut record) in receiver {
+        warn!("Too many connections, rejecting connection of uid {:?}", record.uid);
+        metrics.connection_rejected();
+
+        let result = stream
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .map_err(Error::from)
+            .and_then(|_| {
+                let mut buffer = ReadBuffer::default();
+                let deadline = Some(Instant::now() + Duration::from_secs(1));
+                reject_request(&mut HttpReader::new(&mut stream, &mut buffer, deadline), &Limits::default(),
+                               &mut record, 503, "Service Unavailable", "too many connections")
+            });
+
+        if let Err(ref err) = result {
+            log_error_chain(err);
+        }
 
-    {
-        let config = Arc::make_mut(&mut config);
-
-        // allow: /_ping
-        config.allow_http_path(r"^/_ping$")?;
-        // allow `docker version`
-        config.allow_http_path(r"^(/v[0-9\.]+)?/version$")?;
-        // allow `docker info`
-        config.filter_http_path(r"^(/v[0-9\.]+)?/info$", filters::info)?;
-        // allow `docker ps`:
-        //  /containers/json?..
-        //  /v1.37/containers/json?..
-        config.filter_http_path(r"^(/v[0-9\.]+)?/containers/json(\?.*)?$", filters::list)?;
-        // allow `docker inspect <id>`:
-        //  /containers/ID/json?..
-        //  /v1.37/containers/ID/json?..
-        config.filter_http_path(r"^(/v[0-9\.]+)?/containers//?[a-zA-Z0-9][a-zA-Z0-9_\.-]+/json(\?.*)?$",
-                           filters::inspect)?;
+        if let Some(ref audit_log) = audit_log {
+            audit_log.log(&record);
+        }
+    }
+}
+
+/// A client connection that was admitted by the connection limiter.
+struct Connection {
+    stream: CountingStream<UnixStream>,
+    /// Configuration when the connection was accepted. It is used by all its
+    /// requests, even if the configuration is reloaded in the meantime.
+    config: Arc<Config>,
+    /// Bytes of the next request that were received with the previous one.
+    buffer: ReadBuffer,
+    metrics: Arc<Metrics>,
+    _guard: ConnectionGuard,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.metrics.connection_closed();
+    }
+}
+
+/// State that is shared by the accept loop, the workers, and the event loop.
+struct Proxy {
+    active_config: Arc<RwLock<Arc<Config>>>,
+    audit_log: Option<Arc<AuditLog>>,
+    metrics: Arc<Metrics>,
+    rate_limiter: RateLimiter,
+    pool: ThreadPool,
+    reactor: Reactor,
+}
+
+impl Proxy {
+    fn log(&self, record: &AuditRecord) {
+        if record.method.is_some() {
+            self.metrics.request(record.rule, record.allowed);
+        }
+
+        if let Some(ref audit_log) = self.audit_log {
+            audit_log.log(record);
+        }
+    }
+}
+
+/// Returns when the headers of the next request must be received.
+fn request_deadline(config: &Config) -> Option<Instant> {
+    config.timeouts.request_headers.map(|timeout| Instant::now() + timeout)
+}
+
+/// Watches an idle connection in the event loop, and hands it to a worker
+/// when the next request arrives. If the request does not arrive before
+/// `deadline`, the connection is closed.
+fn wait_for_request(proxy: &Arc<Proxy>, connection: Connection, deadline: Option<Instant>) {
+    let fd = connection.stream.as_raw_fd();
+    let on_readable = {
+        let proxy = Arc::clone(proxy);
+        Box::new(move |timed_out| {
+            if timed_out {
+                debug!("Closing idle connection");
+                return;
+            }
+
+            let mut buf = [0; 1];
+            let len = unsafe {
+                libc::recv(connection.stream.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, 1,
+                           libc::MSG_PEEK | libc::MSG_DONTWAIT)
+            };
+
+            if len > 0 {
+                let worker_proxy = Arc::clone(&proxy);
+                proxy.pool.execute(move || handle_connection(&worker_proxy, connection, deadline));
+            } else if len < 0 && io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock {
+                wait_for_request(&proxy, connection, deadline);
+            }
+            // otherwise the client closed the connection
+        })
+    };
+
+    if let Err(e) = proxy.reactor.wait_readable(fd, deadline, on_readable) {
+        error!("Failed to watch client connection: {}", e);
+    }
+}
+
+/// Handles a request of a client connection and writes the outcome in the audit log.
+fn handle_connection(proxy: &Arc<Proxy>, mut connection: Connection, headers_deadline: Option<Instant>) {
+    let config = Arc::clone(&connection.config);
+    let mut record = new_audit_record(&connection.stream.inner);
+    // bytes in the buffer belong to the next request
+    let bytes_read = connection.stream.bytes_read - connection.buffer.len() as u64;
+    let bytes_written = connection.stream.bytes_written;
+
+    let result = handle_client(&mut connection.stream, &mut connection.buffer, &config, &proxy.metrics,
+                               &proxy.rate_limiter, &mut record, headers_deadline);
+
+    record.bytes_in = connection.stream.bytes_read - connection.buffer.len() as u64 - bytes_read;
+    record.bytes_out = connection.stream.bytes_written - bytes_written;
+
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(ref err) => {
+            record.error = Some(err.to_string());
+            log_error_chain(err);
+            Outcome::Close
+        }
+    };
+
+    match outcome {
+        Outcome::Close => proxy.log(&record),
+        Outcome::KeepAlive => {
+            proxy.log(&record);
+            let deadline = request_deadline(&config);
+            if connection.buffer.is_empty() {
+                wait_for_request(proxy, connection, deadline);
+            } else {
+                // the next request was received with this one, so the event loop will not notice it
+                let worker_proxy = Arc::clone(proxy);
+                proxy.pool.execute(move || handle_connection(&worker_proxy, connection, deadline));
+            }
+        }
+        Outcome::Upgrade(upstream, from_upstream) => {
+            let client = match connection.stream.inner.try_clone() {
+                Ok(client) => client,
+                Err(e) => {
+                    record.error = Some(e.to_string());
+                    proxy.log(&record);
+                    return;
+                }
+            };
+
+            let from_client = connection.buffer.take();
+
+            proxy.metrics.upgraded_opened();
+            let on_close = {
+                let proxy = Arc::clone(proxy);
+                Box::new(move |bytes_in, bytes_out| {
+                    proxy.metrics.upgraded_closed();
+                    record.bytes_in += bytes_in;
+                    record.bytes_out += bytes_out;
+                    proxy.log(&record);
+                    drop(connection);
+                })
+            };
+
+            let idle_timeout = config.timeouts.upgraded_idle;
+            let pending = (from_client, from_upstream);
+            if let Err(e) = proxy.reactor.splice(Box::new(client), upstream, pending, idle_timeout, on_close) {
+                error!("Failed to watch upgraded connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Serves the metrics in Prometheus text format at `/metrics`.
+fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) {
+    for stream in listener.incoming() {
+        let result = stream
+            .and_then(|stream| stream.set_write_timeout(Some(Duration::from_secs(1))).map(|_| stream))
+            .map_err(Error::from)
+            .and_then(|mut stream| {
+                // requests are served one by one, so slow clients must not block the others
+                let deadline = Some(Instant::now() + Duration::from_secs(1));
+                let limits = Limits::default();
+                let mut buffer = ReadBuffer::default();
+                let mut hdr_buf = Vec::new();
+                let mut headers = vec![httparse::EMPTY_HEADER; limits.headers];
+                let http_req = read_http_headers(&mut HttpReader::new(&mut stream, &mut buffer, deadline),
+                                                 &mut headers, &mut hdr_buf, limits.header_bytes)?;
+
+                let (status, content_type, content) = match http_req.req()?.path {
+                    Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+                    _ => ("404 Not Found", "text/plain", "Not Found\n".to_owned()),
+                };
+
+                write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                                Connection: close\r\n\r\n{}",
+                       status, content_type, content.len(), content)?;
+                Ok(())
+            });
+
+        if let Err(ref err) = result {
+            log_error_chain(err);
+        }
+    }
+}
+
+/// Gives a filter function with its name, for `Config::filter_http_path`.
+macro_rules! filter {
+    ($filter:path) => ((stringify!($filter), $filter as FilterFn))
+}
+
+/// Loads the configuration and registers the white-listed paths.
+fn load_config(arg_matches: &ArgMatches) -> Result<Config> {
+    let mut config = Config::from_arg_matches(arg_matches)?;
+
+    // allow: /_ping
+    config.allow_http_path("ping", r"^(/v[0-9\.]+)?/_ping$")?;
+    // allow `docker version`
+    config.filter_http_path("version", r"^(/v[0-9\.]+)?/version$", filter!(filters::version))?;
+    // allow `docker info`
+    config.filter_http_path("info", r"^(/v[0-9\.]+)?/info$", filter!(filters::info))?;
+    // allow `docker ps`:
+    //  /containers/json?..
+    //  /v1.37/containers/json?..
+    config.filter_http_path("containers-list", r"^(/v[0-9\.]+)?/containers/json$", filter!(filters::list))?;
+    config.rewrite_http_path(r"^(/v[0-9\.]+)?/containers/json(\?.*)?$", filters::list_query)?;
+    // allow `docker inspect <id>`:
+    //  /containers/ID/json?..
+    //  /v1.37/containers/ID/json?..
+    config.filter_http_path("container-inspect", r"^(/v[0-9\.]+)?/containers//?[a-zA-Z0-9][a-zA-Z0-9_\.-]+/json$",
+                       filter!(filters::inspect))?;
+    // allow `docker images`:
+    //  /images/json?..
+    //  /v1.37/images/json?..
+    config.filter_http_path("images-list", r"^(/v[0-9\.]+)?/images/json$", filter!(filters::images_list))?;
+    // allow `docker image inspect <name>`:
+    //  /images/NAME/json
+    //  /v1.37/images/REPO/NAME:TAG/json
+    config.filter_http_path("image-inspect", r"^(/v[0-9\.]+)?/images/[a-zA-Z0-9][a-zA-Z0-9_\.:@-]*(/[a-zA-Z0-9][a-zA-Z0-9_\.:@-]*)*/json$",
+                       filter!(filters::image_inspect))?;
+    // allow `docker network ls`:
+    //  /networks?..
+    //  /v1.37/networks?..
+    config.filter_http_path("networks-list", r"^(/v[0-9\.]+)?/networks$", filter!(filters::networks_list))?;
+    // allow `docker network inspect <id>`:
+    //  /networks/ID?..
+    //  /v1.37/networks/ID?..
+    config.filter_http_path("network-inspect", r"^(/v[0-9\.]+)?/networks/[a-zA-Z0-9][a-zA-Z0-9_\.-]*$",
+                       filter!(filters::network_inspect))?;
+    // allow `docker volume ls`:
+    //  /volumes?..
+    //  /v1.37/volumes?..
+    config.filter_http_path("volumes-list", r"^(/v[0-9\.]+)?/volumes$", filter!(filters::volumes_list))?;
+    // allow `docker service ls` and `docker service inspect <id>`:
+    //  /services?..
+    //  /v1.37/services/ID?..
+    config.filter_http_path("services-list", r"^(/v[0-9\.]+)?/services$", filter!(filters::services_list))?;
+    config.filter_http_path("service-inspect", r"^(/v[0-9\.]+)?/services/[a-zA-Z0-9][a-zA-Z0-9_\.-]*$",
+                       filter!(filters::service_inspect))?;
+    // allow `docker service ps` and `docker inspect <task id>`:
+    //  /tasks?..
+    //  /v1.37/tasks/ID
+    config.filter_http_path("tasks-list", r"^(/v[0-9\.]+)?/tasks$", filter!(filters::tasks_list))?;
+    config.filter_http_path("task-inspect", r"^(/v[0-9\.]+)?/tasks/[a-zA-Z0-9][a-zA-Z0-9_\.-]*$",
+                       filter!(filters::task_inspect))?;
+    // allow `docker node ls` and `docker node inspect <id>`:
+    //  /nodes?..
+    //  /v1.37/nodes/ID
+    config.filter_http_path("nodes-list", r"^(/v[0-9\.]+)?/nodes$", filter!(filters::nodes_list))?;
+    config.filter_http_path("node-inspect", r"^(/v[0-9\.]+)?/nodes/[a-zA-Z0-9][a-zA-Z0-9_\.-]*$",
+                       filter!(filters::node_inspect))?;
+
+    // query parameters of the rules, the other ones are stripped. `size` of
+    // containers is not allowed since it makes docker scan their file systems.
+    const BOOL: &str = r"^(0|1|true|false)$";
+    const INT: &str = r"^-?[0-9]+$";
+    const ANY: &str = r"";
+    config.allow_query_params("containers-list", &[("all", BOOL), ("limit", INT), ("filters", ANY)])?;
+    config.allow_query_params("images-list", &[("all", BOOL), ("digests", BOOL), ("filters", ANY)])?;
+    config.allow_query_params("networks-list", &[("filters", ANY)])?;
+    config.allow_query_params("network-inspect", &[("verbose", BOOL), ("scope", r"^(swarm|global|local)$")])?;
+    config.allow_query_params("volumes-list", &[("filters", ANY)])?;
+    config.allow_query_params("services-list", &[("filters", ANY)])?;
+    config.allow_query_params("service-inspect", &[("insertDefaults", BOOL)])?;
+    config.allow_query_params("tasks-list", &[("filters", ANY)])?;
+    config.allow_query_params("nodes-list", &[("filters", ANY)])?;
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Blocks `signal` in the current thread, and in the threads that will be spawned by it,
+/// so it can be received with `wait_signal`.
+fn block_signal(signal: libc::c_int) -> io::Result<libc::sigset_t> {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, signal);
+
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+
+        Ok(set)
+    }
+}
+
+fn wait_signal(set: &libc::sigset_t) -> io::Result<libc::c_int> {
+    let mut signal = 0;
+    let ret = unsafe { libc::sigwait(set, &mut signal) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(signal)
+}
+
+/// Reloads the configuration every time `SIGHUP` is received. New connections use the
+/// new configuration, while the ones that are in-flight keep the old one. If the new
+/// configuration is invalid, the old one is kept.
+fn reload_on_sighup(set: libc::sigset_t, arg_matches: ArgMatches, active_config: Arc<RwLock<Arc<Config>>>) {
+    loop {
+        if let Err(e) = wait_signal(&set) {
+            error!("Failed to wait for SIGHUP: {}", e);
+            return;
+        }
+
+        info!("Received SIGHUP, reloading configuration");
+
+        match load_config(&arg_matches) {
+            Ok(config) => {
+                let mut active_config = locks::write(&active_config);
+                if config.audit_log != active_config.audit_log ||
+                   config.metrics_listen != active_config.metrics_listen ||
+                   config.workers != active_config.workers {
+                    warn!("Changes of audit_log, metrics_listen, and workers are applied after restart");
+                }
+                *active_config = Arc::new(config);
+                info!("Configuration reloaded");
+            }
+            Err(ref err) => {
+                log_error_chain(err);
+                error!("Invalid configuration, keeping the old one");
+            }
+        }
+    }
+}
+
+fn current_config(active_config: &RwLock<Arc<Config>>) -> Arc<Config> {
+    Arc::clone(&locks::read(active_config))
+}
+
+fn run(arg_matches: ArgMatches<'static>) -> Result<()> {
+    // must be called before any thread is spawned
+    let sighup = block_signal(libc::SIGHUP).chain_err(|| "Failed to block SIGHUP")?;
+
+    let config = load_config(&arg_matches)?;
+
+    let audit_log = match config.audit_log {
+        Some(ref path) => Some(Arc::new(AuditLog::open(path)?)),
+        None => None,
+    };
+
+    let metrics = Arc::new(Metrics::new());
+
+    if let Some(ref addr) = config.metrics_listen {
+        let listener = TcpListener::bind(addr.as_str())
+            .chain_err(|| format!("Failed to listen for metrics on `{}`", addr))?;
+        let metrics = Arc::clone(&metrics);
+        std::thread::spawn(move || serve_metrics(listener, metrics));
     }
 
     // create docker_guard_dir
@@ -459,15 +1605,70 @@ fn run(arg_matches: ArgMatches) -> Result<()> {
     let listener = UnixListener::bind(&docker_guard_sock)
         .chain_err(|| format!("Failed to create `{}` socket", docker_guard_sock.to_string_lossy()))?;
 
+    let config_workers = config.workers;
+    let active_config = Arc::new(RwLock::new(Arc::new(config)));
+    {
+        let active_config = Arc::clone(&active_config);
+        std::thread::spawn(move || reload_on_sighup(sighup, arg_matches, active_config));
+    }
+
+    let proxy = Arc::new(Proxy {
+        active_config,
+        audit_log,
+        metrics,
+        rate_limiter: RateLimiter::new(),
+        pool: ThreadPool::new(config_workers),
+        reactor: Reactor::new().chain_err(|| "Failed to create event loop")?,
+    });
+    {
+        let proxy = Arc::clone(&proxy);
+        std::thread::spawn(move || {
+            if let Err(e) = proxy.reactor.run() {
+                error!("Event loop failed: {}", e);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    let connection_limiter = ConnectionLimiter::new();
+
+    let (rejected, rejected_receiver) = mpsc::sync_channel(64);
+    {
+        let audit_log = proxy.audit_log.clone();
+        let metrics = Arc::clone(&proxy.metrics);
+        std::thread::spawn(move || reject_connections(rejected_receiver, audit_log, metrics));
+    }
+
     for stream in listener.incoming() {
         match stream {
-            Ok(mut stream) => {
-                let config = Arc::clone(&config);
-                std::thread::spawn(move || {
-                    if let Err(ref err) = handle_client(&mut stream, config) {
-                        log_error_chain(err);
+            Ok(stream) => {
+                let config = current_config(&proxy.active_config);
+                let record = new_audit_record(&stream);
+
+                let guard = match connection_limiter.acquire(&config, record.uid) {
+                    Some(guard) => guard,
+                    None => {
+                        // if too many connections are waiting to be rejected, just close it
+                        rejected.try_send((stream, record)).ok();
+                        continue;
                     }
-                });
+                };
+
+                // a worker writes the response, so a client that does not read it must not block the worker
+                if let Err(e) = stream.set_write_timeout(config.timeouts.response_write) {
+                    warn!("Failed to set the write timeout of client: {}", e);
+                }
+
+                proxy.metrics.connection_opened();
+                let connection = Connection {
+                    stream: CountingStream::new(stream),
+                    config: Arc::clone(&config),
+                    buffer: ReadBuffer::default(),
+                    metrics: Arc::clone(&proxy.metrics),
+                    _guard: guard,
+                };
+                // the connection occupies a worker only after the client sends a request
+                wait_for_request(&proxy, connection, request_deadline(&config));
             }
             Err(e) => {
                 return Err(Error::from(e)).chain_err(|| "Failed to accept incoming connections")
@@ -478,6 +1679,196 @@ fn run(arg_matches: ArgMatches) -> Result<()> {
     Ok(())
 }
 
+fn format_list(list: &[&str]) -> String {
+    if list.is_empty() {
+        "-".to_owned()
+    } else {
+        list.join(", ")
+    }
+}
+
+fn to_strs(list: &[String]) -> Vec<&str> {
+    list.iter().map(|x| x.as_str()).collect()
+}
+
+fn format_header_fields(fields: &[(String, String)]) -> String {
+    let fields: Vec<String> = fields.iter().map(|(name, value)| format!("{}: {}", name, value)).collect();
+    format_list(&to_strs(&fields))
+}
+
+fn format_api_version(version: Option<ApiVersion>) -> String {
+    version.map_or_else(|| "-".to_owned(), |v| v.to_string())
+}
+
+fn format_timeout(timeout: Option<Duration>) -> String {
+    timeout.map_or_else(|| "-".to_owned(), |t| format!("{}s", t.as_secs()))
+}
+
+fn format_rate_limit(limit: Option<RateLimit>) -> String {
+    limit.map_or_else(|| "-".to_owned(), |l| format!("{}/s burst {}", l.rate, l.burst))
+}
+
+/// Loads and validates the configuration, and prints the effective rules.
+fn check_config(arg_matches: &ArgMatches) -> Result<()> {
+    let config = load_config(arg_matches)?;
+
+    println!("Configuration is valid.");
+    println!();
+    println!("Docker host: {}", config.docker_host);
+    println!("White-listed environment variables: {}", format_list(&config.env_whitelist()));
+    println!("White-listed labels: {}", format_list(&config.label_whitelist()));
+    println!("Scope labels: {}", format_list(&to_strs(config.scope_labels())));
+    let (min, max, pinned) = config.api_versions();
+    println!("API versions: min {}, max {}, pinned {}",
+             format_api_version(min), format_api_version(max), format_api_version(pinned));
+    println!("Audit log: {}", config.audit_log.as_deref().unwrap_or("-"));
+    println!("Metrics: {}", config.metrics_listen.as_deref().unwrap_or("-"));
+    println!("Rate limit: {}", format_rate_limit(config.rate_limit()));
+    println!("Workers: {}", config.workers);
+    println!("Max connections: {}, per client {}", config.max_connections,
+             config.max_connections_per_client.map_or_else(|| "-".to_owned(), |x| x.to_string()));
+    let timeouts = config.timeouts;
+    println!("Timeouts: request headers {}, request body {}, connect {}, response headers {}, response idle {}, \
+              response write {}, upgraded idle {}",
+             format_timeout(timeouts.request_headers), format_timeout(timeouts.request_body),
+             format_timeout(timeouts.connect), format_timeout(timeouts.response_headers),
+             format_timeout(timeouts.response_idle), format_timeout(timeouts.response_write),
+             format_timeout(timeouts.upgraded_idle));
+    let limits = config.limits;
+    println!("Limits: header bytes {}, headers {}, request body {}, response body {}",
+             limits.header_bytes, limits.headers, limits.request_body, limits.response_body);
+    let headers = &config.headers;
+    println!("Request headers: remove {}, add {}", format_list(&to_strs(&headers.request_remove)),
+             format_header_fields(&headers.request_add));
+    println!("Response headers: remove {}, add {}", format_list(&to_strs(&headers.response_remove)),
+             format_header_fields(&headers.response_add));
+    println!();
+
+    println!("{:<20} {:<9} {:<26} {:<16} PATH", "RULE", "METHODS", "FILTER", "RATE LIMIT");
+    for rule in config.rules() {
+        println!("{:<20} {:<9} {:<26} {:<16} {}",
+                 rule.name, rule.methods(), rule.filter_name().unwrap_or("-"),
+                 format_rate_limit(config.rule_rate_limit(rule.name)), rule.regex());
+    }
+    println!();
+
+    println!("Query parameters:");
+    for rule in config.rules() {
+        println!("  {:<18} {}", rule.name, format_list(&rule.query_params()));
+    }
+    println!();
+
+    println!("Rewritten paths:");
+    for path in config.rewritten_paths() {
+        println!("  {}", path);
+    }
+
+    Ok(())
+}
+
+/// Prints what docker-guard would do with a request, without contacting docker.
+/// Returns `false` if request would be denied.
+fn explain(arg_matches: &ArgMatches, method: &str, path: &str) -> Result<bool> {
+    let config = load_config(arg_matches)?;
+
+    let mut headers = [];
+    let http_req = Http::Req(httparse::Request {
+        method: Some(method),
+        path: Some(path),
+        version: Some(1),
+        headers: &mut headers,
+    });
+
+    let mut filter_fn = None;
+    let mut http_error = None;
+    let mut record = AuditRecord::new();
+    let rewrite = filter_request(&config, None, &http_req, &mut filter_fn, &mut http_error, &mut record)?;
+
+    println!("Request:  {} {}", method, path);
+
+    let rewrite = match rewrite {
+        Some(rewrite) => rewrite,
+        None => {
+            match http_error {
+                Some((code, reason, msg)) => println!("Decision: deny ({} {}: {})", code, reason, msg),
+                None => println!("Decision: deny (no rule matched)"),
+            }
+            return Ok(false);
+        }
+    };
+
+    println!("Decision: allow");
+    println!("Rule:     {}", record.rule.unwrap_or("-"));
+    let filter_name = record.rule
+        .and_then(|name| config.rules().iter().find(|rule| rule.name == name))
+        .and_then(|rule| rule.filter_name());
+    println!("Filter:   {}", filter_name.unwrap_or("none"));
+    println!("Forward:  {} {}", method, rewrite.path.as_deref().unwrap_or(path));
+
+    Ok(true)
+}
+
+/// Runs the filter of the rule that matches `path` on a captured docker response,
+/// and prints the filtered content. Returns `false` if response would be dropped.
+fn replay(arg_matches: &ArgMatches, path: &str, status: u16, file: Option<&str>) -> Result<bool> {
+    let config = load_config(arg_matches)?;
+
+    let mut content = Vec::new();
+    match file {
+        Some(file) => {
+            File::open(file)
+                .and_then(|mut f| f.read_to_end(&mut content))
+                .chain_err(|| format!("Failed to read `{}`", file))?;
+        }
+        None => {
+            io::stdin().read_to_end(&mut content).chain_err(|| "Failed to read stdin")?;
+        }
+    }
+
+    let mut req_headers = [];
+    let http_req = Http::Req(httparse::Request {
+        method: Some("GET"),
+        path: Some(path),
+        version: Some(1),
+        headers: &mut req_headers,
+    });
+
+    let mut filter_fn = None;
+    let mut http_error = None;
+    let mut record = AuditRecord::new();
+    if filter_request(&config, None, &http_req, &mut filter_fn, &mut http_error, &mut record)?.is_none() {
+        match http_error {
+            Some((code, reason, msg)) => bail!("Request is denied ({} {}: {})", code, reason, msg),
+            None => bail!("Request is denied (no rule matched)"),
+        }
+    }
+
+    let mut res_headers = [];
+    let res = httparse::Response {
+        version: Some(1),
+        code: Some(status),
+        reason: Some(""),
+        headers: &mut res_headers,
+    };
+
+    match filter_fn {
+        Some(filter_fn) => {
+            if !filter_fn(&config, http_req.req()?, &res, &mut content)? {
+                eprintln!("Response is dropped by `{}` rule", record.rule.unwrap_or("-"));
+                return Ok(false);
+            }
+        }
+        None => eprintln!("Response is not filtered by `{}` rule", record.rule.unwrap_or("-")),
+    }
+
+    match serde_json::from_slice::<serde_json::Value>(&content) {
+        Ok(json) => println!("{}", serde_json::to_string_pretty(&json)?),
+        Err(_) => io::stdout().write_all(&content)?,
+    }
+
+    Ok(true)
+}
+
 fn log_error_chain(err: &Error) {
     error!("Error: {}", err);
     for err in err.iter().skip(1) {
@@ -501,14 +1892,47 @@ fn main() {
              .value_name("VAR_NAME")
              .multiple(true)
              .value_delimiter(",")
+             .require_delimiter(true)
              .help("White-list an environment variable. Can be used multiple times."))
+        .arg(Arg::with_name("LABEL_WHITELIST")
+             .short("l")
+             .long("label")
+             .env("LABEL_WHITELIST")
+             .takes_value(true)
+             .value_name("LABEL_NAME")
+             .multiple(true)
+             .value_delimiter(",")
+             .require_delimiter(true)
+             .help("White-list a label. A trailing `*` matches any suffix. Can be used multiple times."))
+        .arg(Arg::with_name("SCOPE_LABELS")
+             .short("s")
+             .long("scope-label")
+             .env("SCOPE_LABELS")
+             .takes_value(true)
+             .value_name("LABEL[=VALUE]")
+             .multiple(true)
+             .value_delimiter(",")
+             .require_delimiter(true)
+             .help("Show only containers with this label. Can be used multiple times."))
         .arg(Arg::with_name("CONFIG")
              .short("c")
              .long("config")
              .env("CONFIG")
              .takes_value(true)
-             .default_value("/etc/docker-guard/config.yml")
+             .default_value(DEFAULT_CONFIG_FILE)
              .help("Specify a config file"))
+        .arg(Arg::with_name("AUDIT_LOG")
+             .long("audit-log")
+             .env("AUDIT_LOG")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Write a JSON line for every request in this file, or `-` for stdout"))
+        .arg(Arg::with_name("METRICS_LISTEN")
+             .long("metrics-listen")
+             .env("METRICS_LISTEN")
+             .takes_value(true)
+             .value_name("ADDR:PORT")
+             .help("Serve Prometheus metrics at `/metrics` of this address"))
         .arg(Arg::with_name("DOCKER_HOST")
              .short("H")
              .long("host")
@@ -516,6 +1940,28 @@ fn main() {
              .takes_value(true)
              .default_value("unix:///var/run/docker.sock")
              .help("Docker socket to connect"))
+        .subcommand(SubCommand::with_name("check-config")
+                    .about("Validate the configuration and print the effective rules"))
+        .subcommand(SubCommand::with_name("explain")
+                    .about("Print which rule matches a request and whether it is allowed")
+                    .arg(Arg::with_name("METHOD")
+                         .required(true)
+                         .help("HTTP method of the request, e.g. GET"))
+                    .arg(Arg::with_name("PATH")
+                         .required(true)
+                         .help("Path of the request, e.g. /v1.37/containers/ID/json")))
+        .subcommand(SubCommand::with_name("replay")
+                    .about("Filter a captured docker response as if it was received for a GET request")
+                    .arg(Arg::with_name("STATUS")
+                         .long("status")
+                         .takes_value(true)
+                         .default_value("200")
+                         .help("HTTP status code of the response"))
+                    .arg(Arg::with_name("PATH")
+                         .required(true)
+                         .help("Path of the request, e.g. /v1.37/containers/ID/json"))
+                    .arg(Arg::with_name("FILE")
+                         .help("File with the response content. If omitted, it is read from stdin")))
         .get_matches();
 
     let log_level =
@@ -531,8 +1977,202 @@ fn main() {
         .filter_level(log_level)
         .init();
 
-    if let Err(ref e) = run(matches) {
+    let result = match matches.subcommand() {
+        ("check-config", _) => check_config(&matches),
+        ("explain", Some(sub_matches)) => {
+            let method = sub_matches.value_of("METHOD").unwrap();
+            let path = sub_matches.value_of("PATH").unwrap();
+            match explain(&matches, method, path) {
+                Ok(true) => Ok(()),
+                Ok(false) => std::process::exit(2),
+                Err(e) => Err(e),
+            }
+        }
+        ("replay", Some(sub_matches)) => {
+            let path = sub_matches.value_of("PATH").unwrap();
+            let file = sub_matches.value_of("FILE");
+            let result = value_t!(sub_matches, "STATUS", u16)
+                .chain_err(|| "Invalid status code")
+                .and_then(|status| replay(&matches, path, status, file));
+            match result {
+                Ok(true) => Ok(()),
+                Ok(false) => std::process::exit(2),
+                Err(e) => Err(e),
+            }
+        }
+        _ => run(matches),
+    };
+
+    if let Err(ref e) = result {
         log_error_chain(e);
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_headers(http: &[u8]) -> Result<()> {
+        let mut reader = http;
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut hdr_buf = Vec::new();
+        read_http_headers(&mut reader, &mut headers, &mut hdr_buf, 8192).map(|_| ())
+    }
+
+    fn read_chunk(chunk: &[u8]) -> Result<(bool, Vec<u8>)> {
+        let mut reader = chunk;
+        let mut content_buf = Vec::new();
+        let more = read_http_chunk(&mut reader, &mut content_buf, 1024)?;
+        Ok((more, content_buf))
+    }
+
+    fn is_malformed<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error(ErrorKind::MalformedHttp(_), _)))
+    }
+
+    #[test]
+    fn framing_accepts_single_length_or_chunked() {
+        assert!(read_headers(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n").is_ok());
+        assert!(read_headers(b"POST / HTTP/1.1\r\ncontent-length: 0\r\n\r\n").is_ok());
+        assert!(read_headers(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").is_ok());
+        assert!(read_headers(b"POST / HTTP/1.1\r\ntransfer-encoding: Chunked\r\n\r\n").is_ok());
+        assert!(read_headers(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn framing_rejects_length_and_chunked() {
+        assert!(is_malformed(read_headers(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n")));
+        assert!(is_malformed(read_headers(
+            b"POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\ncontent-length: 5\r\n\r\n")));
+    }
+
+    #[test]
+    fn framing_rejects_ambiguous_length() {
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n")));
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n")));
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n")));
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nContent-Length: 0x5\r\n\r\n")));
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nContent-Length:\r\n\r\n")));
+    }
+
+    #[test]
+    fn framing_rejects_other_transfer_encodings() {
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n")));
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n")));
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nTransfer-Encoding: identity\r\n\r\n")));
+        assert!(is_malformed(read_headers(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n")));
+    }
+
+    #[test]
+    fn headers_reject_obs_fold() {
+        assert!(is_malformed(read_headers(b"POST / HTTP/1.1\r\nX-Foo: a\r\n b\r\n\r\n")));
+        assert!(is_malformed(read_headers(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\tTransfer-Encoding: chunked\r\n\r\n")));
+    }
+
+    #[test]
+    fn chunk_is_read() {
+        assert_eq!(read_chunk(b"5\r\nhello\r\n").unwrap(), (true, b"hello".to_vec()));
+        assert_eq!(read_chunk(b"A\r\nhello worl\r\n").unwrap(), (true, b"hello worl".to_vec()));
+        assert_eq!(read_chunk(b"0\r\n\r\n").unwrap(), (false, Vec::new()));
+    }
+
+    #[test]
+    fn chunk_extensions_are_ignored() {
+        assert_eq!(read_chunk(b"5;ext=1\r\nhello\r\n").unwrap(), (true, b"hello".to_vec()));
+        assert_eq!(read_chunk(b"0;ext\r\n\r\n").unwrap(), (false, Vec::new()));
+    }
+
+    #[test]
+    fn chunk_rejects_invalid_size() {
+        assert!(is_malformed(read_chunk(b"+5\r\nhello\r\n")));
+        assert!(is_malformed(read_chunk(b"zz\r\nhello\r\n")));
+        assert!(is_malformed(read_chunk(b" 5\r\nhello\r\n")));
+        assert!(is_malformed(read_chunk(b"\r\nhello\r\n")));
+        assert!(is_malformed(read_chunk(b"10000000000000000000\r\n")));
+    }
+
+    #[test]
+    fn chunk_rejects_missing_crlf() {
+        assert!(is_malformed(read_chunk(b"5\r\nhelloXX")));
+    }
+
+    #[test]
+    fn chunk_larger_than_limit_is_rejected() {
+        let result = read_chunk(b"401\r\n");
+        assert!(matches!(result, Err(Error(ErrorKind::ContentTooLarge(1024), _))));
+    }
+
+    fn normalize(target: &str) -> Option<String> {
+        normalize_request_target(target).ok()
+    }
+
+    #[test]
+    fn target_is_kept_if_normal() {
+        assert_eq!(normalize("/v1.37/containers/json").as_deref(), Some("/v1.37/containers/json"));
+        assert_eq!(normalize("/").as_deref(), Some("/"));
+        assert_eq!(normalize("/containers/json?all=1&size=0").as_deref(), Some("/containers/json?all=1&size=0"));
+    }
+
+    #[test]
+    fn target_rejects_encoded_slash() {
+        assert_eq!(normalize("/v1.37/containers%2Fjson"), None);
+        assert_eq!(normalize("/v1.37/containers%2fjson"), None);
+    }
+
+    #[test]
+    fn target_rejects_control_characters() {
+        assert_eq!(normalize("/containers/a%0Ab/json"), None);
+        assert_eq!(normalize("/containers/a%00b/json"), None);
+    }
+
+    #[test]
+    fn target_is_decoded() {
+        assert_eq!(normalize("/v1.37/%63ontainers/json").as_deref(), Some("/v1.37/containers/json"));
+        assert_eq!(normalize("/containers/a%20b/json").as_deref(), Some("/containers/a%20b/json"));
+    }
+
+    #[test]
+    fn target_dot_segments_are_removed() {
+        assert_eq!(normalize("/../../version").as_deref(), Some("/version"));
+        assert_eq!(normalize("/v1.37/containers/../images/json").as_deref(), Some("/v1.37/images/json"));
+        assert_eq!(normalize("/./version/.").as_deref(), Some("/version"));
+        assert_eq!(normalize("/%2e%2e/version").as_deref(), Some("/version"));
+    }
+
+    #[test]
+    fn target_empty_segments_are_removed() {
+        assert_eq!(normalize("//v1.37//containers///json").as_deref(), Some("/v1.37/containers/json"));
+        assert_eq!(normalize("/version/").as_deref(), Some("/version"));
+    }
+
+    #[test]
+    fn target_query_is_encoded_again() {
+        assert_eq!(normalize("/containers/json?filters=%7B%7D").as_deref(),
+                   Some("/containers/json?filters=%7B%7D"));
+        assert_eq!(normalize("/containers/json?filters={}").as_deref(), Some("/containers/json?filters=%7B%7D"));
+        assert_eq!(normalize("/containers/json?").as_deref(), Some("/containers/json"));
+    }
+
+    #[test]
+    fn target_absolute_form_is_reduced() {
+        assert_eq!(normalize("http://localhost/v1.37/version").as_deref(), Some("/v1.37/version"));
+        assert_eq!(normalize("HTTPS://localhost:2375/../version?a=1").as_deref(), Some("/version?a=1"));
+        assert_eq!(normalize("http://localhost").as_deref(), Some("/"));
+        assert_eq!(normalize("http://localhost?all=1").as_deref(), Some("/?all=1"));
+        assert_eq!(normalize("http://localhost/a\\b").as_deref(), Some("/a\\b"));
+    }
+
+    #[test]
+    fn target_rejects_invalid_forms() {
+        assert_eq!(normalize("/version#fragment"), None);
+        assert_eq!(normalize("http://localhost/version#fragment"), None);
+        assert_eq!(normalize("http:///version"), None);
+        assert_eq!(normalize("localhost/version"), None);
+        assert_eq!(normalize("ftp://localhost/version"), None);
+        assert_eq!(normalize("*"), None);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use locks::lock;
+
+/// Upper bounds (in seconds) of the buckets of latency histograms.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Rule label of the requests that did not match any rule.
+const NO_RULE: &str = "none";
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; 11],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value <= *le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Metrics that are exposed in Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    upstream_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    filter_errors: Mutex<BTreeMap<&'static str, u64>>,
+    active_connections: AtomicUsize,
+    rejected_connections: AtomicUsize,
+    active_upgraded: AtomicUsize,
+    upgraded_total: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn request(&self, rule: Option<&'static str>, allowed: bool) {
+        let decision = if allowed { "allow" } else { "deny" };
+        *lock(&self.requests).entry((rule.unwrap_or(NO_RULE), decision)).or_insert(0) += 1;
+    }
+
+    pub fn upstream_latency(&self, rule: Option<&'static str>, duration: Duration) {
+        let secs = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        lock(&self.upstream_latency).entry(rule.unwrap_or(NO_RULE)).or_default().observe(secs);
+    }
+
+    pub fn filter_error(&self, rule: Option<&'static str>) {
+        *lock(&self.filter_errors).entry(rule.unwrap_or(NO_RULE)).or_insert(0) += 1;
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn upgraded_opened(&self) {
+        self.upgraded_total.fetch_add(1, Ordering::SeqCst);
+        self.active_upgraded.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn upgraded_closed(&self) {
+        self.active_upgraded.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Renders all metrics in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP docker_guard_requests_total Number of requests by rule and decision.\n");
+        out.push_str("# TYPE docker_guard_requests_total counter\n");
+        for ((rule, decision), count) in lock(&self.requests).iter() {
+            writeln!(out, "docker_guard_requests_total{{rule=\"{}\",decision=\"{}\"}} {}",
+                     rule, decision, count).unwrap();
+        }
+
+        out.push_str("# HELP docker_guard_upstream_duration_seconds Time that docker needed to respond.\n");
+        out.push_str("# TYPE docker_guard_upstream_duration_seconds histogram\n");
+        for (rule, histogram) in lock(&self.upstream_latency).iter() {
+            for (bucket, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+                writeln!(out, "docker_guard_upstream_duration_seconds_bucket{{rule=\"{}\",le=\"{}\"}} {}",
+                         rule, le, bucket).unwrap();
+            }
+            writeln!(out, "docker_guard_upstream_duration_seconds_bucket{{rule=\"{}\",le=\"+Inf\"}} {}",
+                     rule, histogram.count).unwrap();
+            writeln!(out, "docker_guard_upstream_duration_seconds_sum{{rule=\"{}\"}} {}",
+                     rule, histogram.sum).unwrap();
+            writeln!(out, "docker_guard_upstream_duration_seconds_count{{rule=\"{}\"}} {}",
+                     rule, histogram.count).unwrap();
+        }
+
+        out.push_str("# HELP docker_guard_filter_errors_total Number of responses that failed to be filtered.\n");
+        out.push_str("# TYPE docker_guard_filter_errors_total counter\n");
+        for (rule, count) in lock(&self.filter_errors).iter() {
+            writeln!(out, "docker_guard_filter_errors_total{{rule=\"{}\"}} {}", rule, count).unwrap();
+        }
+
+        out.push_str("# HELP docker_guard_active_connections Number of open client connections.\n");
+        out.push_str("# TYPE docker_guard_active_connections gauge\n");
+        writeln!(out, "docker_guard_active_connections {}",
+                 self.active_connections.load(Ordering::SeqCst)).unwrap();
+
+        out.push_str("# HELP docker_guard_rejected_connections_total Number of connections \
+                      that exceeded the connection limits.\n");
+        out.push_str("# TYPE docker_guard_rejected_connections_total counter\n");
+        writeln!(out, "docker_guard_rejected_connections_total {}",
+                 self.rejected_connections.load(Ordering::SeqCst)).unwrap();
+
+        out.push_str("# HELP docker_guard_upgraded_connections Number of open upgraded connections.\n");
+        out.push_str("# TYPE docker_guard_upgraded_connections gauge\n");
+        writeln!(out, "docker_guard_upgraded_connections {}",
+                 self.active_upgraded.load(Ordering::SeqCst)).unwrap();
+
+        out.push_str("# HELP docker_guard_upgraded_connections_total Number of upgraded connections.\n");
+        out.push_str("# TYPE docker_guard_upgraded_connections_total counter\n");
+        writeln!(out, "docker_guard_upgraded_connections_total {}",
+                 self.upgraded_total.load(Ordering::SeqCst)).unwrap();
+
+        out
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use config::Config;
+use locks::lock;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of worker threads that run the jobs in the order they are submitted.
+pub struct ThreadPool {
+    sender: mpsc::Sender<Job>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..size {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || loop {
+                let job = lock(&receiver).recv();
+                match job {
+                    // a panic must not kill the worker, otherwise the pool shrinks
+                    Ok(job) => {
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            error!("Worker thread panicked");
+                        }
+                    }
+                    // pool is dropped
+                    Err(_) => break,
+                }
+            });
+        }
+
+        ThreadPool { sender }
+    }
+
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // workers never exit while the pool exists, so this cannot fail
+        self.sender.send(Box::new(job)).ok();
+    }
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_client: HashMap<Option<u32>, usize>,
+}
+
+/// Counts the open connections, in total and per client. A client is identified by its uid.
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    connections: Arc<Mutex<Connections>>,
+}
+
+/// An open connection that was admitted by `ConnectionLimiter`. It is released when dropped.
+pub struct ConnectionGuard {
+    connections: Arc<Mutex<Connections>>,
+    uid: Option<u32>,
+}
+
+impl ConnectionLimiter {
+    pub fn new() -> ConnectionLimiter {
+        ConnectionLimiter::default()
+    }
+
+    /// Admits a new connection of the client. Returns `None` if `max_connections`
+    /// or `max_connections_per_client` are reached.
+    pub fn acquire(&self, config: &Config, uid: Option<u32>) -> Option<ConnectionGuard> {
+        let mut connections = lock(&self.connections);
+
+        if connections.total >= config.max_connections {
+            return None;
+        }
+
+        let client_connections = connections.per_client.get(&uid).cloned().unwrap_or(0);
+        if let Some(max) = config.max_connections_per_client {
+            if client_connections >= max {
+                return None;
+            }
+        }
+
+        connections.total += 1;
+        connections.per_client.insert(uid, client_connections + 1);
+
+        Some(ConnectionGuard {
+            connections: Arc::clone(&self.connections),
+            uid,
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = lock(&self.connections);
+        connections.total -= 1;
+
+        let remove = match connections.per_client.get_mut(&self.uid) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+
+        if remove {
+            connections.per_client.remove(&self.uid);
+        }
+    }
+}
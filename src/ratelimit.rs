@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use config::{Config, RateLimit};
+use locks::lock;
+
+/// Buckets are identified by the uid of the client, and the name of the rule
+/// or `None` for the bucket of all the requests.
+type BucketKey = (Option<u32>, Option<&'static str>);
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit) -> Bucket {
+        Bucket {
+            tokens: limit.burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.last_refill = now;
+    }
+}
+
+/// Token buckets of the clients. A client is identified by its uid.
+///
+/// Every client has a bucket for all its requests, and a bucket for each rule
+/// that has its own limit. The limits are taken from the configuration of each
+/// request, so they can change after a reload while the buckets are kept.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    /// Takes a token from the buckets of the client. Returns `false` if any of
+    /// the buckets is empty, in which case no token is taken.
+    pub fn acquire(&self, config: &Config, uid: Option<u32>, rule: &'static str) -> bool {
+        let mut limits = Vec::new();
+        if let Some(limit) = config.rate_limit() {
+            limits.push(((uid, None), limit));
+        }
+        if let Some(limit) = config.rule_rate_limit(rule) {
+            limits.push(((uid, Some(rule)), limit));
+        }
+
+        let mut buckets = lock(&self.buckets);
+
+        for (key, limit) in &limits {
+            let bucket = buckets.entry(*key).or_insert_with(|| Bucket::new(limit));
+            bucket.refill(limit);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+        }
+
+        for (key, _) in &limits {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        true
+    }
+}
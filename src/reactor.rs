@@ -0,0 +1,388 @@
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use epoll;
+use libc;
+
+use locks::lock;
+
+use Stream;
+
+/// Called when a connection is readable or closed by the other end, with `false`,
+/// or when its deadline passes, with `true`.
+pub type ReadableFn = Box<dyn FnOnce(bool) + Send>;
+
+/// Called when a spliced connection is closed, with the number of bytes that were
+/// forwarded from the client and to the client.
+pub type SpliceClosedFn = Box<dyn FnOnce(u64, u64) + Send>;
+
+/// Data that was read from one end of a splice and is not yet written to the other one.
+#[derive(Default)]
+struct Pipe {
+    buf: Vec<u8>,
+    pos: usize,
+    bytes: u64,
+}
+
+impl Pipe {
+    fn new(buf: Vec<u8>) -> Pipe {
+        Pipe { buf, pos: 0, bytes: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    /// Moves data from `from` to `to` until one of them would block.
+    /// Returns `false` if `from` was closed.
+    fn forward(&mut self, from: &mut dyn Read, to: &mut dyn Write) -> io::Result<bool> {
+        loop {
+            if self.is_empty() {
+                self.buf.resize(4096, 0);
+                let len = match from.read(&mut self.buf) {
+                    Ok(0) => return Ok(false),
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.buf.clear();
+                        self.pos = 0;
+                        return Ok(true);
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.buf.truncate(len);
+                self.pos = 0;
+            }
+
+            match to.write(&self.buf[self.pos..]) {
+                Ok(len) => {
+                    self.pos += len;
+                    self.bytes += len as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A client connection and an upstream connection that forward data to each other.
+struct Splice {
+    client: Box<dyn Stream>,
+    upstream: Box<dyn Stream>,
+    client_token: u64,
+    upstream_token: u64,
+    from_client: Pipe,
+    to_client: Pipe,
+    idle_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    on_close: SpliceClosedFn,
+}
+
+impl Splice {
+    /// Returns `false` if any of the connections was closed.
+    fn forward(&mut self) -> io::Result<bool> {
+        Ok(self.from_client.forward(self.client.as_read_mut(), self.upstream.as_write_mut())? &&
+           self.to_client.forward(self.upstream.as_read_mut(), self.client.as_write_mut())?)
+    }
+
+    /// Closes the connections and reports the forwarded bytes.
+    fn close(self) {
+        let Splice { client, upstream, from_client, to_client, on_close, .. } = self;
+        drop(client);
+        drop(upstream);
+        on_close(from_client.bytes, to_client.bytes);
+    }
+}
+
+enum Entry {
+    Readable(RawFd, Option<Instant>, ReadableFn),
+    /// The splice is stored under the token of its client.
+    Splice(u64),
+}
+
+#[derive(Default)]
+struct State {
+    next_token: u64,
+    entries: HashMap<u64, Entry>,
+    splices: HashMap<u64, Splice>,
+    /// Deadlines of the entries, or of the splices, by their token.
+    deadlines: BTreeSet<(Instant, u64)>,
+}
+
+impl State {
+    fn token(&mut self) -> u64 {
+        self.next_token += 1;
+        self.next_token
+    }
+}
+
+/// Event loop that watches the connections that are waiting for something to
+/// happen, so they do not occupy a thread each:
+///
+/// * idle client connections, until they send a request.
+/// * upgraded connections, which are forwarded without blocking. None of the
+///   white-listed paths upgrades the connection at the moment.
+///
+/// Deadlines are checked at least once per second.
+pub struct Reactor {
+    epfd: RawFd,
+    state: Mutex<State>,
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Reactor {
+    pub fn new() -> io::Result<Reactor> {
+        Ok(Reactor {
+            epfd: epoll::create(true)?,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    fn add(&self, fd: RawFd, token: u64, events: epoll::Events) -> io::Result<()> {
+        let ev = epoll::Event::new(events, token);
+        epoll::ctl(self.epfd, epoll::ControlOptions::EPOLL_CTL_ADD, fd, ev)
+    }
+
+    fn modify(&self, fd: RawFd, token: u64, events: epoll::Events) -> io::Result<()> {
+        let ev = epoll::Event::new(events, token);
+        epoll::ctl(self.epfd, epoll::ControlOptions::EPOLL_CTL_MOD, fd, ev)
+    }
+
+    fn remove(&self, fd: RawFd) {
+        let ev = epoll::Event::new(epoll::Events::empty(), 0);
+        epoll::ctl(self.epfd, epoll::ControlOptions::EPOLL_CTL_DEL, fd, ev).ok();
+    }
+
+    /// Calls `on_readable` once `fd` is readable, or when `deadline` passes. `on_readable`
+    /// runs in the thread of the event loop, so it must not block.
+    pub fn wait_readable(&self, fd: RawFd, deadline: Option<Instant>, on_readable: ReadableFn) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        let token = state.token();
+        self.add(fd, token, epoll::Events::EPOLLIN)?;
+        state.entries.insert(token, Entry::Readable(fd, deadline, on_readable));
+        if let Some(deadline) = deadline {
+            state.deadlines.insert((deadline, token));
+        }
+        Ok(())
+    }
+
+    /// Forwards data between `client` and `upstream` until one of them is closed, or
+    /// nothing is forwarded for `idle_timeout`. `pending` are the bytes that were already
+    /// received from the client and from upstream, which are forwarded first.
+    ///
+    /// `on_close` is called when the connections are closed, or if they could not be
+    /// watched, in which case they are closed immediately.
+    pub fn splice(
+        &self,
+        client: Box<dyn Stream>,
+        upstream: Box<dyn Stream>,
+        pending: (Vec<u8>, Vec<u8>),
+        idle_timeout: Option<Duration>,
+        on_close: SpliceClosedFn,
+    ) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        let splice = Splice {
+            client,
+            upstream,
+            client_token: state.token(),
+            upstream_token: state.token(),
+            from_client: Pipe::new(pending.0),
+            to_client: Pipe::new(pending.1),
+            idle_timeout,
+            deadline: idle_timeout.map(|timeout| Instant::now() + timeout),
+            on_close,
+        };
+
+        let result = set_nonblocking(splice.client.as_raw_fd())
+            .and_then(|_| set_nonblocking(splice.upstream.as_raw_fd()))
+            .and_then(|_| self.add(splice.client.as_raw_fd(), splice.client_token, epoll::Events::EPOLLIN))
+            .and_then(|_| {
+                self.add(splice.upstream.as_raw_fd(), splice.upstream_token, epoll::Events::EPOLLIN)
+                    .map_err(|e| {
+                        self.remove(splice.client.as_raw_fd());
+                        e
+                    })
+            });
+
+        if let Err(e) = result {
+            drop(state);
+            splice.close();
+            return Err(e);
+        }
+
+        let token = splice.client_token;
+        state.entries.insert(splice.client_token, Entry::Splice(token));
+        state.entries.insert(splice.upstream_token, Entry::Splice(token));
+        if let Some(deadline) = splice.deadline {
+            state.deadlines.insert((deadline, token));
+        }
+        state.splices.insert(token, splice);
+
+        // write the pending bytes and watch the connections accordingly
+        if let Some(splice) = self.forward(&mut state, token) {
+            drop(state);
+            splice.close();
+        }
+        Ok(())
+    }
+
+    /// Stops watching a splice. The caller must close it.
+    fn remove_splice(&self, state: &mut State, token: u64) -> Option<Splice> {
+        let splice = state.splices.remove(&token)?;
+        state.entries.remove(&splice.client_token);
+        state.entries.remove(&splice.upstream_token);
+        if let Some(deadline) = splice.deadline {
+            state.deadlines.remove(&(deadline, token));
+        }
+        self.remove(splice.client.as_raw_fd());
+        self.remove(splice.upstream.as_raw_fd());
+        Some(splice)
+    }
+
+    /// Forwards the data of a splice and updates the events that are watched, i.e. a
+    /// connection is not read until the data that was read from it is written.
+    fn forward(&self, state: &mut State, token: u64) -> Option<Splice> {
+        let closed = {
+            let splice = state.splices.get_mut(&token)?;
+            let result = splice.forward().and_then(|open| {
+                if !open {
+                    return Ok(false);
+                }
+                let mut client_events = epoll::Events::empty();
+                let mut upstream_events = epoll::Events::empty();
+                if splice.from_client.is_empty() {
+                    client_events |= epoll::Events::EPOLLIN;
+                } else {
+                    upstream_events |= epoll::Events::EPOLLOUT;
+                }
+                if splice.to_client.is_empty() {
+                    upstream_events |= epoll::Events::EPOLLIN;
+                } else {
+                    client_events |= epoll::Events::EPOLLOUT;
+                }
+                self.modify(splice.client.as_raw_fd(), splice.client_token, client_events)?;
+                self.modify(splice.upstream.as_raw_fd(), splice.upstream_token, upstream_events)?;
+                Ok(true)
+            });
+
+            if let (Some(deadline), Some(timeout)) = (splice.deadline, splice.idle_timeout) {
+                let new_deadline = Instant::now() + timeout;
+                state.deadlines.remove(&(deadline, token));
+                state.deadlines.insert((new_deadline, token));
+                splice.deadline = Some(new_deadline);
+            }
+
+            match result {
+                Ok(open) => !open,
+                Err(e) => {
+                    debug!("Upgraded connection failed: {}", e);
+                    true
+                }
+            }
+        };
+
+        if !closed {
+            return None;
+        }
+
+        self.remove_splice(state, token)
+    }
+
+    /// Returns how many milliseconds to wait for events, at most one second.
+    fn wait_timeout(&self) -> i32 {
+        let state = lock(&self.state);
+        let timeout = match state.deadlines.iter().next() {
+            Some(&(deadline, _)) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                // round up, otherwise we wake up a bit before the deadline
+                timeout.as_secs() * 1000 + u64::from((timeout.subsec_nanos() + 999_999) / 1_000_000)
+            }
+            None => 1000,
+        };
+        cmp::min(timeout, 1000) as i32
+    }
+
+    /// Runs the event loop. It never returns, unless waiting for events fails.
+    pub fn run(&self) -> io::Result<()> {
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 64];
+
+        loop {
+            let num_of_events = match epoll::wait(self.epfd, self.wait_timeout(), &mut events) {
+                Ok(num_of_events) => num_of_events,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            // callbacks run after the lock is released, so they can use the reactor
+            let mut ready = Vec::new();
+            let mut closed = Vec::new();
+            {
+                let mut state = lock(&self.state);
+
+                for ev in events[..num_of_events].iter() {
+                    let token = ev.data;
+                    match state.entries.remove(&token) {
+                        Some(Entry::Readable(fd, deadline, on_readable)) => {
+                            self.remove(fd);
+                            if let Some(deadline) = deadline {
+                                state.deadlines.remove(&(deadline, token));
+                            }
+                            ready.push((on_readable, false));
+                        }
+                        Some(Entry::Splice(splice_token)) => {
+                            state.entries.insert(token, Entry::Splice(splice_token));
+                            if let Some(splice) = self.forward(&mut state, splice_token) {
+                                closed.push(splice);
+                            }
+                        }
+                        // closed by a previous event
+                        None => {}
+                    }
+                }
+
+                let now = Instant::now();
+                while let Some(&(deadline, token)) = state.deadlines.iter().next() {
+                    if deadline > now {
+                        break;
+                    }
+                    state.deadlines.remove(&(deadline, token));
+
+                    match state.entries.remove(&token) {
+                        Some(Entry::Readable(fd, _, on_readable)) => {
+                            self.remove(fd);
+                            ready.push((on_readable, true));
+                        }
+                        Some(Entry::Splice(splice_token)) => {
+                            if let Some(splice) = self.remove_splice(&mut state, splice_token) {
+                                debug!("Upgraded connection is idle, closing it");
+                                closed.push(splice);
+                            }
+                        }
+                        None => {}
+                    }
+                }
+            }
+
+            for (on_readable, timed_out) in ready {
+                on_readable(timed_out);
+            }
+
+            for splice in closed {
+                splice.close();
+            }
+        }
+    }
+}